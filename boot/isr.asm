@@ -125,14 +125,20 @@ stub_name:
 
 	; TODO store segment descriptors only if 32 bit must be supported
 
-	; swap user and kernel gs registers
-	; TODO dont bother when already in kernel mode
+	; swap user and kernel gs registers, only if interrupted in user mode (cs rpl != 0). cs follows
+	; the 15 registers, int no, error code and rip
+	test qword [rsp + 8*18], 3
+	jz %%kernel_entry
 	swapgs
+%%kernel_entry:
 
 	; call handler
 	call stub_handler
 
+	test qword [rsp + 8*18], 3
+	jz %%kernel_exit
 	swapgs
+%%kernel_exit:
 
 	; restore registers
 	pop rax
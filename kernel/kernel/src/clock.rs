@@ -1,19 +1,59 @@
-use core::ops::{BitAnd, Shr};
+//! Tickless timekeeping with the PIT. Rather than interrupting at a fixed rate, channel 0 is used
+//! in one-shot mode and reprogrammed on every interrupt for the next deadline requested by the
+//! scheduler. The counter is only 16 bits, so it still fires at least every ~55ms to keep time.
+//!
+//! Time is measured with the TSC if it is invariant, calibrated against the PIT on boot, otherwise
+//! from the PIT counter.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::time::Duration;
 
+use common::{info, Bit};
 use modular_bitfield::prelude::*;
 
 use crate::io::Port;
-use crate::irq;
+use crate::irq::{self, InterruptsDisabled};
+use crate::{scheduler, shared_data};
 
 const PIT_CHANNEL0_DATA: Port = Port::new(0x40);
 // const PIT_CHANNEL2_DATA: Port = Port::new(0x42);
 const PIT_COMMAND: Port = Port::new(0x43);
 
-const TICKS_PER_SECOND: u64 = 120;
+const PIT_HZ: u64 = 1_193_182;
+
+/// Longest one-shot period the 16 bit counter can be programmed for, ~55ms
+const MAX_COUNT: u64 = 0xFFFF;
+
+/// Read-back command for channel 0, latching both status and count
+const PIT_READ_BACK_CHANNEL0: u8 = 0b1100_0010;
+
+/// PIT counts to measure the TSC over to find its frequency, 10ms
+const TSC_CALIBRATION_COUNT: u64 = PIT_HZ / 100;
+
+/// Only accessed with interrupts disabled
+struct OneShot {
+    /// PIT-based nanoseconds since boot at which the current period was programmed
+    start_ns: u64,
+
+    /// Count the current period was programmed with
+    count: u64,
+
+    /// [monotonic_ns] at which the current period expires
+    expiry_ns: u64,
+}
+
+static mut ONE_SHOT: OneShot = OneShot {
+    start_ns: 0,
+    count: 0,
+    expiry_ns: 0,
+};
 
-/// Total number of ticks since boot
-static mut TICKS: u64 = 0;
+/// TSC value on boot, once calibrated
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// TSC frequency, 0 if the TSC is not usable
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
 
 #[bitfield]
 struct PitCommand {
@@ -21,7 +61,7 @@ struct PitCommand {
     bcd_or_binary: B1,
 
     /// Operating mode
-    /// e.g. 011: square wave
+    /// e.g. 000: interrupt on terminal count, 011: square wave
     mode: B3,
 
     // Access mode
@@ -32,14 +72,14 @@ struct PitCommand {
     channel: B2,
 }
 
-fn set_interval(hz: u64) {
-    let divisor = 1193180 / hz;
-    assert_ne!(divisor, 0);
+/// Starts counting down from `count`, raising IRQ 0 once on reaching 0
+fn program_one_shot(count: u64) {
+    debug_assert!(count > 0 && count <= MAX_COUNT);
 
     let cmd = {
         let mut cmd = PitCommand::new();
         cmd.set_bcd_or_binary(0); // binary
-        cmd.set_mode(3); // square wave
+        cmd.set_mode(0); // interrupt on terminal count
         cmd.set_rw(3); // lo and hi bytes
         cmd.set_channel(0); // channel 0
         cmd
@@ -49,23 +89,163 @@ fn set_interval(hz: u64) {
         // write command
         PIT_COMMAND.write_u8(core::mem::transmute(cmd));
 
-        // write divisor
-        PIT_CHANNEL0_DATA.write_u8(divisor.bitand(0xFF) as u8); // lo
-        PIT_CHANNEL0_DATA.write_u8(divisor.shr(8) as u8); // hi
+        // write count
+        PIT_CHANNEL0_DATA.write_u8(count as u8); // lo
+        PIT_CHANNEL0_DATA.write_u8((count >> 8) as u8); // hi
+    }
+}
+
+/// (output pin is high i.e. terminal count reached, current count)
+fn read_back() -> (bool, u64) {
+    unsafe {
+        PIT_COMMAND.write_u8(PIT_READ_BACK_CHANNEL0);
+        let status = PIT_CHANNEL0_DATA.read_u8();
+        let lo = PIT_CHANNEL0_DATA.read_u8() as u64;
+        let hi = PIT_CHANNEL0_DATA.read_u8() as u64;
+        (status.bit(7), hi << 8 | lo)
     }
 }
+
+fn counts_to_ns(counts: u64) -> u64 {
+    counts * 1_000_000_000 / PIT_HZ
+}
+
+fn ns_to_counts(ns: u64) -> u64 {
+    (ns as u128 * PIT_HZ as u128 / 1_000_000_000) as u64
+}
+
 extern "C" fn on_clock(_ctx: *const irq::InterruptContext) {
+    let now = monotonic_ns();
+    shared_data::on_tick(now);
+    let deadline = scheduler::on_timer(now);
+
+    // safety: interrupts are disabled in handlers
     unsafe {
-        TICKS += 1;
+        let pit_now = pit_now_ns();
+        start_period(
+            pit_now,
+            now,
+            deadline.map(|deadline| deadline.saturating_sub(now)),
+        );
+    }
+}
+
+/// Programs the next period for `delay` ns from now, or the longest possible if None
+///
+/// # Safety
+/// Interrupts must be disabled
+unsafe fn start_period(pit_now: u64, now: u64, delay: Option<u64>) {
+    let count = delay
+        .map(ns_to_counts)
+        .unwrap_or(MAX_COUNT)
+        .max(1)
+        .min(MAX_COUNT);
+
+    ONE_SHOT = OneShot {
+        start_ns: pit_now,
+        count,
+        expiry_ns: now + counts_to_ns(count),
     };
+
+    program_one_shot(count);
+}
+
+/// Nanoseconds since boot according to the PIT alone
+///
+/// # Safety
+/// Interrupts must be disabled
+unsafe fn pit_now_ns() -> u64 {
+    if ONE_SHOT.count == 0 {
+        // not started yet
+        return 0;
+    }
+
+    let (fired, current) = read_back();
+    let elapsed = if fired {
+        // keeps counting down from 0xffff after firing
+        ONE_SHOT.count + ((0x10000 - current) & 0xffff)
+    } else {
+        ONE_SHOT.count.saturating_sub(current)
+    };
+
+    ONE_SHOT.start_ns + counts_to_ns(elapsed)
 }
 
 pub fn init() {
-    set_interval(TICKS_PER_SECOND);
+    if has_invariant_tsc() {
+        calibrate_tsc();
+    } else {
+        info!("no invariant TSC, timing is PIT-based only");
+    }
+
+    unsafe {
+        start_period(0, monotonic_ns(), None);
+    }
     irq::register_handler(irq::Irq::Clock, on_clock);
 }
 
+/// TSC must tick at a constant rate regardless of power state to be used for timing
+fn has_invariant_tsc() -> bool {
+    // safety: cpuid is always available in long mode
+    unsafe {
+        let has_tsc = __cpuid(1).edx.bit(4);
+        let max_extended = __cpuid(0x8000_0000).eax;
+        has_tsc && max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx.bit(8)
+    }
+}
+
+/// Busy waits on the PIT, must be called before interrupts are enabled
+fn calibrate_tsc() {
+    program_one_shot(TSC_CALIBRATION_COUNT);
+    let start = unsafe { _rdtsc() };
+    while !read_back().0 {}
+    let end = unsafe { _rdtsc() };
+
+    let hz = (end - start) * PIT_HZ / TSC_CALIBRATION_COUNT;
+    info!("TSC frequency calibrated to {}MHz", hz / 1_000_000);
+
+    // boot time is measured from here
+    TSC_BASE.store(end, Ordering::Relaxed);
+    TSC_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Nanoseconds since boot, from the TSC if usable, otherwise the PIT
+pub fn monotonic_ns() -> u64 {
+    let hz = TSC_HZ.load(Ordering::Relaxed);
+    if hz == 0 {
+        let _irq = InterruptsDisabled::acquire();
+        return unsafe { pit_now_ns() };
+    }
+
+    let elapsed = unsafe { _rdtsc() } - TSC_BASE.load(Ordering::Relaxed);
+    (elapsed as u128 * 1_000_000_000 / hz as u128) as u64
+}
+
+/// (TSC value on boot, TSC frequency), the frequency being 0 if the TSC is not usable
+pub fn tsc_calibration() -> (u64, u64) {
+    (
+        TSC_BASE.load(Ordering::Relaxed),
+        TSC_HZ.load(Ordering::Relaxed),
+    )
+}
+
+/// Ensures the timer fires no later than the given [monotonic_ns] deadline
+pub fn request_wakeup_at(deadline_ns: u64) {
+    let _irq = InterruptsDisabled::acquire();
+
+    // safety: interrupts are disabled
+    unsafe {
+        if deadline_ns >= ONE_SHOT.expiry_ns {
+            // will fire in time anyway
+            return;
+        }
+
+        let pit_now = pit_now_ns();
+        let now = monotonic_ns();
+        start_period(pit_now, now, Some(deadline_ns.saturating_sub(now)));
+    }
+}
+
 pub fn since_boot() -> Duration {
-    let seconds: f64 = unsafe { TICKS as f64 / TICKS_PER_SECOND as f64 };
-    Duration::from_secs_f64(seconds)
+    Duration::from_nanos(monotonic_ns())
 }
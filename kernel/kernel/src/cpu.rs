@@ -31,24 +31,29 @@ impl CpuState {
         Box::leak(Box::new(state))
     }
 
+    /// Returns the previous current thread, if any
+    ///
     /// # Safety
     /// GS.Base must be pointing to a CpuState
-    pub unsafe fn update_current_thread(thread: ThreadRef) {
+    pub unsafe fn update_current_thread(thread: ThreadRef) -> Option<ThreadRef> {
         // just to make sure before we do some screwy stuff
         assert_eq!(core::mem::size_of::<Option<ThreadRef>>(), 8);
 
         let stack = thread.kernel_stack();
-        let some_thread = core::mem::transmute::<Option<ThreadRef>, u64>(Some(thread));
+        let mut some_thread = core::mem::transmute::<Option<ThreadRef>, u64>(Some(thread));
 
         asm!(
             "mov gs:{offset_stack}, {stack}",
-            "mov gs:{offset_thread}, {thread}",
+            "xchg gs:{offset_thread}, {thread}",
             offset_stack = const Self::THREAD_KERNEL_STACK_OFFSET,
             stack = in(reg) stack.address(),
 
             offset_thread = const Self::CURRENT_THREAD_OFFSET,
-            thread = in(reg) some_thread,
-        )
+            thread = inout(reg) some_thread,
+        );
+
+        // we now own the reference that was previously held
+        core::mem::transmute::<u64, Option<ThreadRef>>(some_thread)
     }
 
     /// # Safety
@@ -56,6 +61,17 @@ impl CpuState {
     ///
     /// Panics if current thread not set
     pub unsafe fn current_thread() -> ThreadRef {
+        match Self::current_thread_opt() {
+            Some(thread) => thread,
+            None => panic!("no current thread in cpu state"),
+        }
+    }
+
+    /// None before the scheduler has started
+    ///
+    /// # Safety
+    /// GS.Base must be pointing to a CpuState.
+    pub unsafe fn current_thread_opt() -> Option<ThreadRef> {
         let thread_handle: *const ();
         asm!(
             "mov {}, gs:{offset_thread}",
@@ -65,7 +81,7 @@ impl CpuState {
 
         // None == null
         if thread_handle.is_null() {
-            panic!("no current thread in cpu state")
+            return None;
         }
 
         // reference is definitely Some now
@@ -80,6 +96,6 @@ impl CpuState {
         // decremented
         core::mem::forget(borrowed_ref);
 
-        owned
+        Some(owned)
     }
 }
@@ -2,7 +2,7 @@ use core::ops::{Shl, Shr};
 
 use modular_bitfield::prelude::*;
 
-use crate::descriptor_tables::tss::IST_IDX_DOUBLE_FAULT;
+use crate::descriptor_tables::tss::{IST_IDX_DOUBLE_FAULT, IST_IDX_MACHINE_CHECK, IST_IDX_NMI};
 use crate::descriptor_tables::DescriptorTablePointer;
 use crate::irq;
 use common::InitializedGlobal;
@@ -128,7 +128,9 @@ impl Default for InterruptDescriptorTable {
 
         table.register(0, externs::isr0);
         table.register(1, externs::isr1);
-        table.register(2, externs::isr2);
+        table
+            .register(2, externs::isr2)
+            .set_ist_index(Some(IST_IDX_NMI));
         table.register(3, externs::isr3);
         table.register(4, externs::isr4);
         table.register(5, externs::isr5);
@@ -146,7 +148,9 @@ impl Default for InterruptDescriptorTable {
         table.register(15, externs::isr15);
         table.register(16, externs::isr16);
         table.register(17, externs::isr17);
-        table.register(18, externs::isr18);
+        table
+            .register(18, externs::isr18)
+            .set_ist_index(Some(IST_IDX_MACHINE_CHECK));
         table.register(19, externs::isr19);
         table.register(20, externs::isr20);
         table.register(21, externs::isr21);
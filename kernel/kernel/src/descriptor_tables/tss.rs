@@ -19,11 +19,16 @@ pub struct TaskStateSegment {
     iomap_base: u16,
 }
 
+// exceptions that can happen at any time, even on a corrupt or overflowing stack, get their own
 pub const IST_IDX_DOUBLE_FAULT: usize = 0;
+pub const IST_IDX_NMI: usize = 1;
+pub const IST_IDX_MACHINE_CHECK: usize = 2;
 
 const IST_STACK_SIZE: usize = kilobytes(8) as usize;
 
 static mut IST_STACK_DOUBLE_FAULT: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut IST_STACK_NMI: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut IST_STACK_MACHINE_CHECK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
 
 impl Default for TaskStateSegment {
     fn default() -> Self {
@@ -46,6 +51,8 @@ impl TaskStateSegment {
         unsafe {
             // TODO allocate stack dynamically instead of static array
             tss.register_ist(IST_IDX_DOUBLE_FAULT, &mut IST_STACK_DOUBLE_FAULT);
+            tss.register_ist(IST_IDX_NMI, &mut IST_STACK_NMI);
+            tss.register_ist(IST_IDX_MACHINE_CHECK, &mut IST_STACK_MACHINE_CHECK);
 
             TSS.init(tss);
             let ptr = TSS.get() as *mut Self;
@@ -62,6 +69,11 @@ impl TaskStateSegment {
 
     pub fn set_privilege_stack(&mut self, pl: u8, stack: VirtualAddress) {
         common::trace!("tss priv stack[{}] = {:?}", pl, stack);
+        self.set_privilege_stack_quietly(pl, stack);
+    }
+
+    /// No logging, for use on every context switch
+    pub fn set_privilege_stack_quietly(&mut self, pl: u8, stack: VirtualAddress) {
         self.privilege_stacks[pl as usize] = stack;
     }
 }
@@ -106,11 +106,12 @@ impl TryFrom<&InterruptContext> for Exception {
 }
 
 impl Exception {
-    pub fn handle(self, ctx: &InterruptContext) {
+    /// `ctx` is restored on return, and may be modified to resume somewhere else
+    pub fn handle(self, ctx: &mut InterruptContext) {
         use Exception::*;
 
         match self {
-            PageFault(pf) => pf.handle(),
+            PageFault(pf) => pf.handle(ctx),
             _ => panic!("unhandled exception {:?}\n{:?}", self, ctx),
         }
     }
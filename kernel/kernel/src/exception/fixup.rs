@@ -0,0 +1,34 @@
+//! Exception fixup table, for kernel code that is allowed to fault on bad user pointers. Each
+//! entry pairs the address of an instruction that may fault with the address to resume at instead
+//! of panicking. Entries are emitted into the `.ex_table` section next to the instruction, e.g.
+//! by the user copy routines in [crate::memory].
+
+#[repr(C)]
+struct Fixup {
+    fault_rip: u64,
+    fixup_rip: u64,
+}
+
+extern "C" {
+    #[link_name = "_ex_table"]
+    static EX_TABLE_START: Fixup;
+
+    #[link_name = "_ex_table_end"]
+    static EX_TABLE_END: Fixup;
+}
+
+/// Address to resume at if the instruction at `rip` faults
+pub fn find_fixup(rip: u64) -> Option<u64> {
+    // safety: bounds of the table defined by the linker script
+    let table = unsafe {
+        let start = &EX_TABLE_START as *const Fixup;
+        let end = &EX_TABLE_END as *const Fixup;
+        let len = end.offset_from(start) as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+
+    table
+        .iter()
+        .find(|fixup| fixup.fault_rip == rip)
+        .map(|fixup| fixup.fixup_rip)
+}
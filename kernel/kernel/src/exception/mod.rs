@@ -1,4 +1,5 @@
 mod exception;
+mod fixup;
 mod page_fault;
 
 pub use exception::Exception;
@@ -2,9 +2,11 @@ use common::*;
 use core::fmt::{Debug, Error, Formatter};
 
 use crate::cpu::CpuState;
+use crate::exception::fixup::find_fixup;
+use crate::irq::InterruptContext;
 use crate::memory::{frame_allocator, AddressSpace, FrameAllocator, ProcessUserStacks, Stacks};
 use enumflags2::BitFlags;
-use memory::{DemandMapping, VirtualAddress};
+use memory::{DemandMapping, VirtualAddress, VIRT_USERSPACE_MAX};
 
 #[derive(Debug)]
 pub struct PageFaultException {
@@ -33,7 +35,10 @@ impl PageFaultException {
         }
     }
 
-    pub fn handle(self) {
+    /// Resolves the fault so the faulting instruction can be retried. If it can't be resolved but
+    /// the kernel faulted on a user address at an instruction with a fixup, `ctx` resumes at the
+    /// fixup instead
+    pub fn handle(self, ctx: &mut InterruptContext) {
         // TODO get from current process block instead
         // TODO on error, either kill process or kernel panic
 
@@ -42,20 +47,32 @@ impl PageFaultException {
                 unhandled!("{}", $msg)
             });
             ($fmt:expr, $($arg:tt)*) => ({
-                panic!("unhandled page fault {:?}: {}", self, format_args!($fmt, $($arg)*));
+                if let Some(fixup) = self.fixup(ctx) {
+                    debug!(
+                        "recovering from page fault {:?} at rip {:#x}: {}",
+                        self,
+                        ctx.rip,
+                        format_args!($fmt, $($arg)*)
+                    );
+                    ctx.rip = fixup;
+                    return;
+                }
+
+                panic!("unhandled page fault {:?}: {}", self, format_args!($fmt, $($arg)*))
             });
         }
 
         let mut addr_space = AddressSpace::current();
 
         if self.flags.contains(PageFaultFlag::Present) {
-            panic!("page fault on present page: {:?}", self);
+            unhandled!("page fault on present page");
         }
 
         // fetch mapping
-        let (_level, mapping) = addr_space
-            .get_absent_mapping(self.addr)
-            .unwrap_or_else(|e| panic!("nonsensical page fault at {:?}: {}", self.addr, e));
+        let (_level, mapping) = match addr_space.get_absent_mapping(self.addr) {
+            Ok(mapping) => mapping,
+            Err(e) => unhandled!("nonsensical page fault: {}", e),
+        };
 
         match mapping.on_demand() {
             DemandMapping::None => {
@@ -89,11 +106,20 @@ impl PageFaultException {
                             unhandled!("failed to grow stack: {}", err);
                         }
                     }
-                    None => panic!("stack overflow: {:?}", self),
+                    None => unhandled!("stack overflow"),
                 }
             }
         };
     }
+
+    /// Only kernel accesses to user memory are recoverable
+    fn fixup(&self, ctx: &InterruptContext) -> Option<u64> {
+        if ctx.is_from_user() || self.addr.address() >= VIRT_USERSPACE_MAX {
+            return None;
+        }
+
+        find_fixup(ctx.rip)
+    }
 }
 
 impl Debug for PageFaultFlags {
@@ -19,9 +19,13 @@ pub trait Msr: Sized {
     }
 
     unsafe fn store(&self) {
-        let value = self.value();
-        common::trace!("{} = {:#x}", Self::NAME, value);
+        common::trace!("{} = {:#x}", Self::NAME, self.value());
+        self.store_quietly()
+    }
 
+    /// As [store](Msr::store) but without logging, for hot paths like context switches
+    unsafe fn store_quietly(&self) {
+        let value = self.value();
         let lo = value as u32;
         let hi = (value >> 32) as u32;
         asm!("wrmsr", in("ecx") Self::MSR, in("eax") lo, in("edx") hi, options(nostack))
@@ -60,6 +64,10 @@ mod msrs {
     #[derive(Copy, Clone)]
     pub struct LStar(u64);
 
+    /// RFLAGS bits cleared on SYSCALL.
+    #[derive(Copy, Clone)]
+    pub struct SfMask(u64);
+
     /// Ring 0 and Ring 3 Segment bases, as well as SYSCALL EIP.
     #[bitfield]
     #[derive(Copy, Clone)]
@@ -89,6 +97,9 @@ mod msrs {
     #[derive(Copy, Clone)]
     pub struct GsBase(u64);
 
+    #[derive(Copy, Clone)]
+    pub struct FsBase(u64);
+
     impl Msr for Efer {
         const MSR: u32 = 0xC000_0080;
         const NAME: &'static str = "EFER";
@@ -115,6 +126,19 @@ mod msrs {
         }
     }
 
+    impl Msr for SfMask {
+        const MSR: u32 = 0xC000_0084;
+        const NAME: &'static str = "SFMASK";
+
+        fn with_value(val: u64) -> Self {
+            Self(val)
+        }
+
+        fn value(&self) -> u64 {
+            self.0
+        }
+    }
+
     impl Msr for Star {
         const MSR: u32 = 0xC000_0081;
         const NAME: &'static str = "STAR";
@@ -141,6 +165,19 @@ mod msrs {
         }
     }
 
+    impl Msr for FsBase {
+        const MSR: u32 = 0xC000_0100;
+        const NAME: &'static str = "FS.Base";
+
+        fn with_value(val: u64) -> Self {
+            Self(val)
+        }
+
+        fn value(&self) -> u64 {
+            self.0
+        }
+    }
+
     impl Msr for GsBase {
         const MSR: u32 = 0xC000_0101;
         const NAME: &'static str = "GS.Base";
@@ -4,6 +4,8 @@ use common::*;
 
 use crate::exception::Exception;
 use crate::io::Port;
+use crate::process;
+use crate::scheduler::{self, CpuMode};
 use core::convert::TryFrom;
 
 const PIC_MASTER_COMMAND: Port = Port::new(0x20);
@@ -122,23 +124,37 @@ pub fn is_in_interrupt() -> bool {
 }
 
 #[no_mangle]
-pub extern "C" fn irq_handler(ctx: *const InterruptContext) {
-    let _guard = InterruptGuard::init();
+pub extern "C" fn irq_handler(ctx: *mut InterruptContext) {
+    // safety: points to the registers pushed by the isr stub, restored from here on return
+    let ctx: &mut InterruptContext = unsafe { &mut *ctx };
+    let _user = UserInterruptAccounting::enter(ctx);
 
-    let ctx: &InterruptContext = unsafe { &*ctx };
-    let irq = (ctx.int_no - PIC_MASTER_OFFSET as u64) as usize; // remap to original irq
-    debug_assert!(irq < IRQ_HANDLER_COUNT);
+    {
+        let _guard = InterruptGuard::init();
 
-    // TODO spurious irqs
+        let irq = (ctx.int_no - PIC_MASTER_OFFSET as u64) as usize; // remap to original irq
+        debug_assert!(irq < IRQ_HANDLER_COUNT);
 
-    // call handler
-    unsafe {
-        if let Some(handler) = IRQ_HANDLERS.get_unchecked(irq as usize) {
-            handler(ctx);
+        // TODO spurious irqs
+
+        // call handler
+        unsafe {
+            if let Some(handler) = IRQ_HANDLERS.get_unchecked(irq as usize) {
+                handler(ctx);
+            }
+
+            // acknowledge
+            eoi(irq);
         }
+    }
+
+    // interrupts from userspace arrive on the current thread's own kernel stack, so it's safe to
+    // switch away here and iret back to userspace when it's next scheduled
+    if ctx.is_from_user() {
+        scheduler::preemption_point();
 
-        // acknowledge
-        eoi(irq);
+        // the isr stub irets to whatever is in the context, which may now be an APC routine
+        process::deliver_pending_apc(ctx);
     }
 }
 
@@ -152,16 +168,47 @@ unsafe fn eoi(irq: usize) {
 }
 
 #[no_mangle]
-pub extern "C" fn fault_handler(ctx: *const InterruptContext) {
+pub extern "C" fn fault_handler(ctx: *mut InterruptContext) {
     let _guard = InterruptGuard::init();
 
-    let ctx: &InterruptContext = unsafe { &*ctx };
-    match Exception::try_from(ctx) {
+    // safety: points to the registers pushed by the isr stub, restored from here on return
+    let ctx: &mut InterruptContext = unsafe { &mut *ctx };
+    let _user = UserInterruptAccounting::enter(ctx);
+    match Exception::try_from(&*ctx) {
         Ok(exc) => exc.handle(ctx),
         Err(err) => panic!("error handling exception: {}", err),
     }
 }
 
+/// Charges cpu time around interrupts from userspace, see [scheduler::charge_current]
+struct UserInterruptAccounting(bool);
+
+impl UserInterruptAccounting {
+    fn enter(ctx: &InterruptContext) -> Self {
+        let from_user = ctx.is_from_user();
+        if from_user {
+            scheduler::charge_current(CpuMode::User);
+        }
+
+        Self(from_user)
+    }
+}
+
+impl Drop for UserInterruptAccounting {
+    fn drop(&mut self) {
+        if self.0 {
+            scheduler::charge_current(CpuMode::Kernel);
+        }
+    }
+}
+
+impl InterruptContext {
+    /// Interrupted code was running in ring 3
+    pub fn is_from_user(&self) -> bool {
+        self.cs & 3 != 0
+    }
+}
+
 impl InterruptGuard {
     fn init() -> Self {
         #[cfg(debug_assertions)]
@@ -191,6 +238,34 @@ pub fn disable_interrupts() {
     unsafe { asm!("cli") };
 }
 
+/// Disables interrupts until dropped, then restores the interrupt flag to its previous state.
+/// Can be nested
+#[must_use]
+pub struct InterruptsDisabled {
+    were_enabled: bool,
+}
+
+impl InterruptsDisabled {
+    pub fn acquire() -> Self {
+        let rflags: u64;
+        unsafe {
+            asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+        }
+
+        Self {
+            were_enabled: rflags.bit(9),
+        }
+    }
+}
+
+impl Drop for InterruptsDisabled {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            unsafe { asm!("sti") };
+        }
+    }
+}
+
 impl Debug for InterruptContext {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         // safety: packed field access
@@ -31,9 +31,13 @@ mod memory;
 mod multiboot;
 mod panic;
 mod process;
+mod rtc;
+mod scheduler;
 mod serial;
+mod shared_data;
 mod spinlock;
 mod start;
+mod sync;
 mod syscall;
 mod vga;
 
@@ -3,6 +3,7 @@ use crate::memory::phys::{frame_allocator, FrameAllocator};
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
 use enumflags2::BitFlags;
+use memory::VirtualAddress;
 pub use memory::{
     MemoryError, MemoryProvider, PageTableHierarchy, PhysicalFrame, RawAddressSpace, P1, P2, P4,
 };
@@ -85,6 +86,26 @@ impl<'p> AddressSpace<'p> {
         Ok(addr_space)
     }
 
+    /// Unmaps the given range and frees the frames backing it, returning how many were freed. The
+    /// TLB is flushed even if this address space isn't current, as kernel mappings are shared by
+    /// every address space
+    pub fn unmap_and_free(&mut self, start: VirtualAddress, size: u64) -> Result<u64, MemoryError> {
+        let mut freed = 0;
+        self.0.unmap_range(start, size, |frame| {
+            // safety: just unmapped, so nothing else refers to it
+            let frame = unsafe { PhysicalFrame::new(frame) };
+            if let Err(err) = frame_allocator().free(frame) {
+                common::warn!("failed to free frame {:?}: {}", frame.address(), err);
+            }
+
+            freed += 1;
+        })?;
+
+        // reloading cr3 flushes all non-global entries
+        unsafe { Self::current().load_unconditionally() };
+        Ok(freed)
+    }
+
     pub fn borrow<'space>(&self) -> AddressSpaceRef<'p, 'space> {
         // safety: lifetime is still restricted
         let addr_space = unsafe { self.0.clone() };
@@ -3,6 +3,7 @@ mod heap;
 mod init;
 mod phys;
 mod stack;
+mod user;
 
 pub use address_space::{AddressSpace, AddressSpaceRef};
 pub use init::init;
@@ -11,6 +12,7 @@ pub use phys::{frame_allocator, FrameAllocator, FrameFlags};
 pub use stack::{
     KernelInterruptStacks, ProcessKernelStacks, ProcessUserStacks, StackGrowth, Stacks,
 };
+pub use user::{copy_from_user, copy_to_user, UserData, UserPtr, UserSlice};
 
 /// Matches boot/long_mode.asm
 const KERNEL_IDENTITY_MAPPING: u64 = megabytes(32);
@@ -61,10 +61,14 @@ mod dumb {
     use crate::multiboot::{multiboot_memory_map_t, MemoryRegionType, MultibootMemoryMap};
     use common::*;
     use enumflags2::BitFlags;
-    use memory::{MemoryError, PhysicalAddress, PhysicalFrame, FRAME_SIZE};
+    use memory::{MemoryError, PhysicalAddress, PhysicalFrame, VirtualAddress, FRAME_SIZE};
 
     pub struct DumbFrameAllocator {
         frames: Frames,
+
+        /// Most recently freed frame, which holds the address of the one freed before it in its
+        /// first 8 bytes, or 0 for the last
+        freed: Option<PhysicalAddress>,
     }
 
     #[derive(Clone)]
@@ -122,7 +126,27 @@ mod dumb {
                     frame_idx: 0,
                     start: kernel_end,
                 },
+                freed: None,
+            }
+        }
+
+        /// Reuses the most recently freed frame if it satisfies `flags`
+        fn pop_freed(&mut self, flags: BitFlags<FrameFlags>) -> Option<PhysicalFrame> {
+            let frame = self.freed?;
+            if flags.contains(FrameFlags::PreMapped) && frame.address() >= KERNEL_IDENTITY_MAPPING {
+                return None;
             }
+
+            // safety: freed frames are unused, and accessible through the identity mapping
+            let next = unsafe { *VirtualAddress::from_physical(frame).as_ptr::<u64>() };
+            self.freed = if next == 0 {
+                None
+            } else {
+                Some(PhysicalAddress(next))
+            };
+
+            // safety: was allocated by this allocator
+            Some(unsafe { PhysicalFrame::new(frame) })
         }
     }
 
@@ -134,6 +158,10 @@ mod dumb {
                 // return Err(KernelError::NotImplemented);
             }
 
+            if let Some(frame) = self.pop_freed(flags) {
+                return Ok(frame);
+            }
+
             let frame = if flags.contains(FrameFlags::PreMapped) {
                 // need to peek ahead to see if next frame is premapped
 
@@ -159,8 +187,17 @@ mod dumb {
             frame.ok_or(MemoryError::NoFrame)
         }
 
-        fn free(&mut self, _frame: PhysicalFrame) -> Result<(), MemoryError> {
-            unimplemented!()
+        /// Frames are never returned to the bump allocator, but kept in a list to be reused first.
+        /// Frames are after the kernel so never at address 0, which terminates the list
+        fn free(&mut self, frame: PhysicalFrame) -> Result<(), MemoryError> {
+            let next = self.freed.map_or(0, |next| next.address());
+
+            // safety: the caller no longer uses the frame, and it's accessible through the
+            // identity mapping
+            unsafe { *VirtualAddress::from_physical(frame.address()).as_ptr::<u64>() = next };
+
+            self.freed = Some(frame.address());
+            Ok(())
         }
 
         fn relocate_multiboot(&mut self, mmap: &'static multiboot_memory_map_t) {
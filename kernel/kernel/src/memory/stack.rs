@@ -5,6 +5,7 @@ use memory::{
 };
 
 use crate::memory::AddressSpace;
+use alloc::vec::Vec;
 use core::marker::PhantomData;
 use enumflags2::BitFlags;
 
@@ -43,6 +44,10 @@ pub struct StackIndex(u64);
 
 pub struct Stacks<A: StackAllocation> {
     next_stack: u64,
+
+    /// Slots below `next_stack` whose stacks have been freed, reused first
+    freed: Vec<StackIndex>,
+
     _phantom: PhantomData<A>,
 }
 
@@ -56,13 +61,27 @@ impl<A: StackAllocation> Stacks<A> {
     pub fn new() -> Self {
         Self {
             next_stack: 0,
+            freed: Vec::new(),
             _phantom: PhantomData,
         }
     }
 
-    pub fn new_stack(&mut self) -> Result<(VirtualAddress, StackIndex), MemoryError> {
+    /// Maps in the given address space, which doesn't need to be current
+    pub fn new_stack(
+        &mut self,
+        addr_space: &mut AddressSpace,
+    ) -> Result<(VirtualAddress, StackIndex), MemoryError> {
+        if let Some(idx) = self.freed.pop() {
+            return Self::allocate_stack(addr_space, idx, 0)
+                .map(|stack| (stack, idx))
+                .map_err(|err| {
+                    self.freed.push(idx);
+                    err
+                });
+        }
+
         let idx = StackIndex(self.next_stack);
-        let stack = Self::allocate_stack(idx, 0)?;
+        let stack = Self::allocate_stack(addr_space, idx, 0)?;
 
         // increment on success
         self.next_stack += 1;
@@ -70,11 +89,26 @@ impl<A: StackAllocation> Stacks<A> {
         Ok((stack, idx))
     }
 
-    /// Maps in current address space
-    ///
+    /// Unmaps the whole stack containing `stack_top` and frees its frames, so its slot can be
+    /// reused by [new_stack](Self::new_stack). Returns how many frames were freed. The stack must
+    /// not be in use
+    pub fn free_stack(
+        &mut self,
+        addr_space: &mut AddressSpace,
+        stack_top: VirtualAddress,
+    ) -> Result<u64, MemoryError> {
+        let bottom = Self::stack_limit(stack_top);
+        let freed = addr_space.unmap_and_free(bottom, A::MAX_STACK_SIZE)?;
+
+        self.freed
+            .push(StackIndex((bottom.address() - A::BASE) / A::MAX_STACK_SIZE));
+        Ok(freed)
+    }
+
     /// * stack: unique stack index
     /// * slab: slab index in this stack to grow. Starts at 0 and increments for each growth
     fn allocate_stack(
+        addr_space: &mut AddressSpace,
         StackIndex(stack): StackIndex,
         slab: u64,
     ) -> Result<VirtualAddress, MemoryError> {
@@ -96,8 +130,6 @@ impl<A: StackAllocation> Stacks<A> {
             slab_bottom.round_down_to(FRAME_SIZE).address()
         );
 
-        let mut addr_space = AddressSpace::current();
-
         // ensure currently unmapped
         if addr_space.get_absent_mapping(slab_bottom).is_ok() {
             return Err(MemoryError::AlreadyMapped(slab_bottom.address()));
@@ -175,7 +207,23 @@ impl<A: StackAllocation> Stacks<A> {
         })
     }
 
-    pub fn grow_stack(&self, growth: StackGrowth) -> Result<(), MemoryError> {
+    /// Bytes committed by each [grow_stack](Self::grow_stack)
+    pub const fn growth_size() -> u64 {
+        A::STACK_GROWTH_INCREMENT
+    }
+
+    /// Lowest address the stack with the given top can grow down to
+    pub fn stack_limit(stack_top: VirtualAddress) -> VirtualAddress {
+        let offset = stack_top.address() - A::BASE;
+        VirtualAddress(A::BASE + (offset / A::MAX_STACK_SIZE) * A::MAX_STACK_SIZE)
+    }
+
+    /// Maps in the given address space, which doesn't need to be current
+    pub fn grow_stack(
+        &self,
+        addr_space: &mut AddressSpace,
+        growth: StackGrowth,
+    ) -> Result<(), MemoryError> {
         // trust input because it can only be constructed by resolve_required_stack_growth
 
         let stack_bottom = A::BASE + (growth.stack * A::MAX_STACK_SIZE);
@@ -184,8 +232,6 @@ impl<A: StackAllocation> Stacks<A> {
         );
         let slab_bottom = slab_top - A::STACK_GROWTH_INCREMENT;
 
-        let mut addr_space = AddressSpace::current();
-
         // ensure currently unmapped
         if addr_space.get_absent_mapping(slab_bottom).is_ok() {
             return Err(MemoryError::AlreadyMapped(slab_bottom.address()));
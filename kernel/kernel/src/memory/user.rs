@@ -0,0 +1,182 @@
+//! Access to the user address space of the current process from the kernel. User pointers are
+//! only checked to be in the user half of the address space, so may still be unmapped or read-only.
+//! Copies recover from page faults with the exception fixup table and fail with
+//! [SyscallError::BadAddress] instead.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use memory::{VirtualAddress, VIRT_USERSPACE_MAX};
+use syscall::{ApcFrame, SpawnParameters, SyscallError};
+
+/// Types that can be copied to and from userspace, for which any bit pattern is valid
+///
+/// # Safety
+/// Must be plain old data
+pub unsafe trait UserData: Copy {}
+
+unsafe impl UserData for u8 {}
+unsafe impl UserData for u32 {}
+unsafe impl UserData for u64 {}
+unsafe impl UserData for ApcFrame {}
+unsafe impl UserData for SpawnParameters {}
+
+/// Non-null, aligned pointer to a `T` in userspace
+pub struct UserPtr<T: UserData> {
+    addr: u64,
+    _phantom: PhantomData<*mut T>,
+}
+
+/// Range of bytes in userspace, which may be empty
+#[derive(Copy, Clone, Debug)]
+pub struct UserSlice {
+    addr: u64,
+    len: u64,
+}
+
+impl<T: UserData> UserPtr<T> {
+    pub fn new(addr: u64) -> Result<Self, SyscallError> {
+        let size = core::mem::size_of::<T>() as u64;
+        let align = core::mem::align_of::<T>() as u64;
+        if addr == 0 || addr % align != 0 || !in_userspace(addr, size) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        Ok(Self {
+            addr,
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn address(&self) -> VirtualAddress {
+        VirtualAddress::new(self.addr)
+    }
+
+    pub fn read(&self) -> Result<T, SyscallError> {
+        let mut value = MaybeUninit::<T>::uninit();
+
+        // safety: T is plain old data so can be initialised from any bytes
+        unsafe {
+            let dst = core::slice::from_raw_parts_mut(
+                value.as_mut_ptr() as *mut u8,
+                core::mem::size_of::<T>(),
+            );
+            copy_from_user(dst, self.addr)?;
+            Ok(value.assume_init())
+        }
+    }
+
+    pub fn write(&self, value: &T) -> Result<(), SyscallError> {
+        // safety: T is plain old data
+        let src = unsafe {
+            core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+        };
+        copy_to_user(self.addr, src)
+    }
+}
+
+impl<T: UserData> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: UserData> Copy for UserPtr<T> {}
+
+impl<T: UserData> Debug for UserPtr<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "UserPtr({:#x})", self.addr)
+    }
+}
+
+impl UserSlice {
+    pub fn new(addr: u64, len: u64) -> Result<Self, SyscallError> {
+        if len != 0 && !in_userspace(addr, len) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        Ok(Self { addr, len })
+    }
+
+    /// Copies the whole range into a new buffer. Fails with [SyscallError::InvalidArguments] if
+    /// longer than `max_len`, so userspace can't make the kernel allocate arbitrarily much
+    pub fn read_to_vec(&self, max_len: u64) -> Result<Vec<u8>, SyscallError> {
+        if self.len > max_len {
+            return Err(SyscallError::InvalidArguments);
+        }
+
+        let mut buf = vec![0u8; self.len as usize];
+        copy_from_user(&mut buf, self.addr)?;
+        Ok(buf)
+    }
+}
+
+/// Copies `dst.len()` bytes from user address `src` into `dst`, failing if the range isn't in
+/// userspace or faults part way through, in which case `dst` may be partially written
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), SyscallError> {
+    if dst.is_empty() {
+        return Ok(());
+    }
+
+    if !in_userspace(src, dst.len() as u64) {
+        return Err(SyscallError::BadAddress);
+    }
+
+    // safety: dst is a valid kernel buffer, and faults on src are recovered from
+    let remaining = unsafe { copy_user_bytes(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+    if remaining == 0 {
+        Ok(())
+    } else {
+        Err(SyscallError::BadAddress)
+    }
+}
+
+/// Copies `src` to user address `dst`, failing if the range isn't in userspace or faults part way
+/// through, in which case it may be partially written
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), SyscallError> {
+    if src.is_empty() {
+        return Ok(());
+    }
+
+    if !in_userspace(dst, src.len() as u64) {
+        return Err(SyscallError::BadAddress);
+    }
+
+    // safety: src is a valid kernel buffer, and faults on dst are recovered from
+    let remaining = unsafe { copy_user_bytes(dst as *mut u8, src.as_ptr(), src.len()) };
+    if remaining == 0 {
+        Ok(())
+    } else {
+        Err(SyscallError::BadAddress)
+    }
+}
+
+fn in_userspace(addr: u64, len: u64) -> bool {
+    addr.checked_add(len)
+        .map(|end| end <= VIRT_USERSPACE_MAX)
+        .unwrap_or(false)
+}
+
+/// Returns the number of bytes not copied, which is non-zero if the copy faulted and was resumed
+/// at the fixup. Only the user side of the copy may fault
+#[naked]
+unsafe extern "C" fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    asm!(
+        // interrupts don't clear the direction flag
+        "cld",
+        "mov rcx, rdx",
+        "2: rep movsb",
+        "xor eax, eax",
+        "ret",
+        // fault
+        "3: mov rax, rcx",
+        "ret",
+        ".pushsection .ex_table, \"a\"",
+        ".balign 8",
+        ".quad 2b, 3b",
+        ".popsection",
+        options(noreturn)
+    )
+}
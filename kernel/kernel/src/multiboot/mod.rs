@@ -67,6 +67,21 @@ impl<'multiboot> CommandLine<'multiboot> {
     }
 }
 
+impl CommandLine<'_> {
+    fn as_str(&self) -> &str {
+        // safety: as for Display
+        unsafe {
+            let mut len = 0;
+            while *self.start.add(len) != 0x00 {
+                len += 1;
+            }
+
+            let bytes = core::slice::from_raw_parts(self.start, len);
+            core::str::from_utf8(bytes).unwrap_or_default()
+        }
+    }
+}
+
 impl Display for CommandLine<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         let mut ptr = self.start;
@@ -95,6 +110,14 @@ impl Multiboot {
         Self(mbi)
     }
 
+    /// The kernel command line contains the given whitespace separated argument. Only valid
+    /// before the identity mapping of low memory is removed
+    pub fn has_arg(&self, arg: &str) -> bool {
+        CommandLine::init(self.0)
+            .map(|cmdline| cmdline.as_str().split_whitespace().any(|a| a == arg))
+            .unwrap_or(false)
+    }
+
     pub fn memory_map(&self) -> Option<MultibootMemoryMap> {
         MultibootMemoryMap::new(self.0)
     }
@@ -0,0 +1,199 @@
+//! Asynchronous procedure calls (APCs) queued to user threads, e.g. for timers, Ctrl-C handling
+//! and cross-thread wakeups. A queued routine runs on its thread the next time the thread returns
+//! to userspace, from either a syscall or an interrupt.
+//!
+//! The interrupted context is pushed onto the user stack as an [ApcFrame], below the red zone,
+//! and the routine is entered as `routine(arg, frame)` in the Windows x64 calling convention with
+//! a null return address. It resumes the interrupted code with the `apc_return` syscall, which
+//! restores every register from the frame, including the result of an interrupted syscall. APCs
+//! are delivered one at a time, the next is delivered by `apc_return` itself.
+//!
+//! Syscalls normally return with sysret, which can't restore every register. Instead, when an APC
+//! is pending or `apc_return` was called, the syscall returns through the slower path that saves
+//! the full user context and passes it to [finish_user_return].
+
+use crate::cpu::CpuState;
+use crate::irq::InterruptContext;
+use crate::memory::UserPtr;
+use common::*;
+use memory::VIRT_USERSPACE_MAX;
+use syscall::{ApcFrame, SyscallError, APC_RED_ZONE};
+
+#[derive(Copy, Clone, Debug)]
+pub struct UserApc {
+    /// User address of the routine
+    pub routine: u64,
+
+    /// Passed to the routine in rcx
+    pub arg: u64,
+}
+
+/// Reserved bit 1 and interrupts enabled
+const USER_RFLAGS_FIXED: u64 = 0x202;
+
+/// CF, PF, AF, ZF, SF, TF, DF and OF
+const USER_RFLAGS_RESTORABLE: u64 = 0xdd5;
+
+/// Home space for 4 register arguments, reserved by the caller in the Windows x64 convention
+const SHADOW_SPACE: u64 = 32;
+
+/// Enters the next APC queued to the current thread, if any, by pushing the user context in `ctx`
+/// onto the user stack and pointing `ctx` at the routine instead. `ctx` must be the context the
+/// current thread returns to userspace with.
+pub fn deliver_pending_apc(ctx: &mut InterruptContext) {
+    // safety: only called on the way back to userspace from thread context
+    let thread = unsafe { CpuState::current_thread() };
+    let apc = match thread.take_user_apc() {
+        Some(apc) => apc,
+        None => return,
+    };
+
+    let frame_addr = match frame_address(ctx.rsp) {
+        Some(addr) if apc.routine < VIRT_USERSPACE_MAX => addr,
+        _ => {
+            warn!(
+                "dropping APC {:?} for thread {:?}, bad routine or stack pointer {:#x}",
+                apc,
+                thread.tid(),
+                ctx.rsp
+            );
+            return;
+        }
+    };
+
+    // rsp is 8 mod 16 on entry, as if the routine was called by a function that reserved shadow
+    // space
+    let routine_rsp = frame_addr - SHADOW_SPACE - 8;
+
+    let pushed = UserPtr::<ApcFrame>::new(frame_addr)
+        .and_then(|frame| frame.write(&ApcFrame::from(&*ctx)))
+        .and_then(|_| UserPtr::<u64>::new(routine_rsp))
+        .and_then(|ret_addr| ret_addr.write(&0));
+    if pushed.is_err() {
+        warn!(
+            "dropping APC {:?} for thread {:?}, couldn't push frame at {:#x}",
+            apc,
+            thread.tid(),
+            frame_addr
+        );
+        return;
+    }
+
+    trace!(
+        "delivering APC {:?} to thread {:?} with frame at {:#x}",
+        apc,
+        thread.tid(),
+        frame_addr
+    );
+
+    ctx.rip = apc.routine;
+    ctx.rsp = routine_rsp;
+    ctx.rcx = apc.arg;
+    ctx.rdx = frame_addr;
+
+    // clear DF as the calling convention requires, and TF so single-stepping doesn't step into it
+    ctx.rflags = USER_RFLAGS_FIXED;
+}
+
+/// Handler for the `apc_return` syscall. Validates the frame and saves it to be restored by
+/// [finish_user_return] on the way out of the syscall, or fails and returns to the caller.
+pub fn begin_apc_return(frame_addr: u64) -> Result<(), SyscallError> {
+    let frame = read_frame(frame_addr)?;
+
+    // safety: syscalls are called from thread context
+    let thread = unsafe { CpuState::current_thread() };
+    thread.set_apc_return_frame(frame);
+    Ok(())
+}
+
+/// The current thread must return from its syscall through [finish_user_return] rather than
+/// sysret
+pub fn needs_full_user_return() -> bool {
+    // safety: syscalls are called from thread context
+    let thread = unsafe { CpuState::current_thread() };
+    thread.has_apc_return_frame() || thread.has_user_apc()
+}
+
+/// Called with the full user context of the current thread on its way back to userspace from a
+/// syscall. Restores the frame passed to `apc_return` if it was called, then delivers the next
+/// pending APC
+pub fn finish_user_return(ctx: &mut InterruptContext) {
+    // safety: syscalls are called from thread context
+    let thread = unsafe { CpuState::current_thread() };
+    if let Some(frame) = thread.take_apc_return_frame() {
+        restore_frame(&frame, ctx);
+    }
+
+    deliver_pending_apc(ctx);
+}
+
+/// Where to push a frame below the given user stack pointer, 16 byte aligned
+fn frame_address(user_rsp: u64) -> Option<u64> {
+    let frame_size = core::mem::size_of::<ApcFrame>() as u64;
+    if user_rsp > VIRT_USERSPACE_MAX {
+        return None;
+    }
+
+    user_rsp
+        .checked_sub(APC_RED_ZONE + frame_size)
+        .map(|addr| addr & !0xf)
+        .filter(|addr| *addr >= SHADOW_SPACE + 8)
+}
+
+fn read_frame(frame_addr: u64) -> Result<ApcFrame, SyscallError> {
+    let frame = UserPtr::<ApcFrame>::new(frame_addr)?.read()?;
+
+    // iretq to a non-canonical address would fault in the kernel
+    if frame.rip >= VIRT_USERSPACE_MAX || frame.rsp >= VIRT_USERSPACE_MAX {
+        return Err(SyscallError::InvalidArguments);
+    }
+
+    Ok(frame)
+}
+
+/// Segment selectors are left as they are, and only the restorable flags are taken
+fn restore_frame(frame: &ApcFrame, ctx: &mut InterruptContext) {
+    ctx.rax = frame.rax;
+    ctx.rbx = frame.rbx;
+    ctx.rcx = frame.rcx;
+    ctx.rdx = frame.rdx;
+    ctx.rsi = frame.rsi;
+    ctx.rdi = frame.rdi;
+    ctx.rbp = frame.rbp;
+    ctx.r8 = frame.r8;
+    ctx.r9 = frame.r9;
+    ctx.r10 = frame.r10;
+    ctx.r11 = frame.r11;
+    ctx.r12 = frame.r12;
+    ctx.r13 = frame.r13;
+    ctx.r14 = frame.r14;
+    ctx.r15 = frame.r15;
+    ctx.rip = frame.rip;
+    ctx.rsp = frame.rsp;
+    ctx.rflags = (frame.rflags & USER_RFLAGS_RESTORABLE) | USER_RFLAGS_FIXED;
+}
+
+impl From<&InterruptContext> for ApcFrame {
+    fn from(ctx: &InterruptContext) -> Self {
+        Self {
+            rax: ctx.rax,
+            rbx: ctx.rbx,
+            rcx: ctx.rcx,
+            rdx: ctx.rdx,
+            rsi: ctx.rsi,
+            rdi: ctx.rdi,
+            rbp: ctx.rbp,
+            r8: ctx.r8,
+            r9: ctx.r9,
+            r10: ctx.r10,
+            r11: ctx.r11,
+            r12: ctx.r12,
+            r13: ctx.r13,
+            r14: ctx.r14,
+            r15: ctx.r15,
+            rip: ctx.rip,
+            rsp: ctx.rsp,
+            rflags: ctx.rflags,
+        }
+    }
+}
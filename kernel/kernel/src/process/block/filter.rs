@@ -0,0 +1,51 @@
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU64, Ordering};
+use syscall::{FilterAction, SyscallNumber, ALL_SYSCALLS, ALWAYS_ALLOWED};
+
+/// Syscalls a process may make, and what happens when it makes any other. Filters can only be
+/// tightened, by removing allowed syscalls or making the action stricter
+pub struct SyscallFilter {
+    /// Bit n is set if syscall n is allowed
+    allowed: AtomicU64,
+
+    /// [FilterAction] as u64
+    action: AtomicU64,
+}
+
+impl Default for SyscallFilter {
+    /// Allows everything
+    fn default() -> Self {
+        Self {
+            allowed: AtomicU64::new(ALL_SYSCALLS),
+            action: AtomicU64::new(FilterAction::Deny as u64),
+        }
+    }
+}
+
+impl SyscallFilter {
+    pub fn inherit(&self) -> Self {
+        let inherited = Self::default();
+        inherited.tighten(self.allowed(), self.action());
+        inherited
+    }
+
+    /// Mask of allowed syscalls
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    pub fn action(&self) -> FilterAction {
+        let action = self.action.load(Ordering::Relaxed);
+        FilterAction::try_from(action).unwrap() // only valid actions are stored
+    }
+
+    pub fn is_allowed(&self, number: SyscallNumber) -> bool {
+        (self.allowed() | ALWAYS_ALLOWED) & (1 << number as u64) != 0
+    }
+
+    /// Allows only syscalls in both the current and the given mask, and uses the stricter action
+    pub fn tighten(&self, allowed: u64, action: FilterAction) {
+        self.allowed.fetch_and(allowed, Ordering::Relaxed);
+        self.action.fetch_max(action as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::process::block::process::ProcessRef;
+use crate::process::block::thread::ThreadRef;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use syscall::HandleRights;
+
+/// Small integer referring to a kernel object in a process' [HandleTable]. Any value is a valid
+/// handle, but not necessarily one that is open. 0 is never open
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct Handle(u64);
+
+/// Reference counted kernel object that a handle refers to
+#[derive(Clone)]
+pub enum KernelObject {
+    Process(ProcessRef),
+    Thread(ThreadRef),
+}
+
+#[derive(Clone, Debug)]
+pub struct HandleEntry {
+    pub object: KernelObject,
+    pub rights: HandleRights,
+}
+
+/// Open handles of a process. Handles are allocated lowest first, so closed ones are reused
+#[derive(Default)]
+pub struct HandleTable {
+    /// Indexed by handle - 1
+    entries: Vec<Option<HandleEntry>>,
+
+    /// Number of Some entries
+    open: usize,
+}
+
+impl HandleTable {
+    pub fn insert(&mut self, entry: HandleEntry) -> Handle {
+        let idx = match self.entries.iter().position(Option::is_none) {
+            Some(idx) => {
+                self.entries[idx] = Some(entry);
+                idx
+            }
+            None => {
+                self.entries.push(Some(entry));
+                self.entries.len() - 1
+            }
+        };
+
+        self.open += 1;
+        Handle::from_index(idx)
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&HandleEntry> {
+        handle
+            .index()
+            .and_then(|idx| self.entries.get(idx))
+            .and_then(Option::as_ref)
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Option<HandleEntry> {
+        let idx = handle.index()?;
+        let removed = self.entries.get_mut(idx)?.take()?;
+        self.open -= 1;
+
+        // shrink past closed handles at the end
+        while let Some(None) = self.entries.last() {
+            self.entries.pop();
+        }
+
+        Some(removed)
+    }
+
+    /// Number of open handles
+    pub fn len(&self) -> usize {
+        self.open
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.open == 0
+    }
+
+    /// New table for a child process, with the handles that have [HandleRights::INHERIT] at the
+    /// same values
+    pub fn inherit(&self) -> Self {
+        let entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .as_ref()
+                    .filter(|entry| entry.rights.contains(HandleRights::INHERIT))
+                    .cloned()
+            })
+            .collect();
+
+        let mut inherited = Self {
+            open: entries.iter().filter(|entry| entry.is_some()).count(),
+            entries,
+        };
+
+        while let Some(None) = inherited.entries.last() {
+            inherited.entries.pop();
+        }
+
+        inherited
+    }
+}
+
+impl Handle {
+    fn from_index(idx: usize) -> Self {
+        Self(idx as u64 + 1)
+    }
+
+    /// None for 0
+    fn index(self) -> Option<usize> {
+        self.0.checked_sub(1).map(|idx| idx as usize)
+    }
+}
+
+impl From<u64> for Handle {
+    fn from(handle: u64) -> Self {
+        Handle(handle)
+    }
+}
+
+impl From<Handle> for u64 {
+    fn from(handle: Handle) -> Self {
+        handle.0
+    }
+}
+
+impl Debug for Handle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Handle({})", self.0)
+    }
+}
+
+impl Debug for KernelObject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            KernelObject::Process(process) => write!(f, "Process({:?})", process.pid()),
+            KernelObject::Thread(thread) => write!(f, "Thread({:?})", thread.tid()),
+        }
+    }
+}
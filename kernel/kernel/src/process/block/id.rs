@@ -1,7 +1,7 @@
 use common::Deref;
 use core::fmt::{Debug, Formatter};
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 #[repr(transparent)]
 pub struct Pid(u64);
 
@@ -37,6 +37,19 @@ impl Drop for OwnedPid {
     }
 }
 
+/// Any value is a valid pid, but not necessarily one that is in use
+impl From<u64> for Pid {
+    fn from(pid: u64) -> Self {
+        Pid(pid)
+    }
+}
+
+impl From<Pid> for u64 {
+    fn from(pid: Pid) -> Self {
+        pid.0
+    }
+}
+
 impl Debug for Pid {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "Pid({:#x})", self.0)
@@ -0,0 +1,127 @@
+use crate::process::error::ProcessError;
+use crate::process::ExitCode;
+use crate::scheduler::CpuTime;
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Resources that can be limited per process
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Resource {
+    /// Bytes of user memory mapped as usable, including grown stacks
+    CommittedMemory = 0,
+
+    /// Number of live threads
+    Threads = 1,
+
+    /// Number of open handles
+    Handles = 2,
+
+    /// Nanoseconds of CPU time across all threads, in both user and kernel mode
+    CpuTime = 3,
+}
+
+const RESOURCE_COUNT: usize = 4;
+
+/// No limit. The largest value that can be returned from a syscall
+pub const UNLIMITED: u64 = i64::MAX as u64;
+
+/// Exit code of a process killed for exceeding its CPU time limit
+pub const EXIT_CODE_CPU_LIMIT: ExitCode = 0xC000_0001;
+
+/// Limits of a process and its current usage of the counted resources. Children inherit the
+/// limits of their parent at creation, but have their own usage. Limits can only be lowered.
+pub struct ResourceLimits {
+    limits: [AtomicU64; RESOURCE_COUNT],
+
+    /// Only [Resource::CommittedMemory] and [Resource::Handles] are counted here, threads are
+    /// counted by the process and CPU time by its [CpuTime]
+    usage: [AtomicU64; RESOURCE_COUNT],
+}
+
+impl Default for ResourceLimits {
+    /// Unlimited
+    fn default() -> Self {
+        Self {
+            limits: [
+                AtomicU64::new(UNLIMITED),
+                AtomicU64::new(UNLIMITED),
+                AtomicU64::new(UNLIMITED),
+                AtomicU64::new(UNLIMITED),
+            ],
+            usage: Default::default(),
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// Copies the limits but not the usage
+    pub fn inherit(&self) -> Self {
+        let inherited = Self::default();
+        for (dst, src) in inherited.limits.iter().zip(self.limits.iter()) {
+            dst.store(src.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        inherited
+    }
+
+    pub fn limit(&self, resource: Resource) -> u64 {
+        self.limits[resource as usize].load(Ordering::Relaxed)
+    }
+
+    /// Fails if the new limit is higher than the current one
+    pub fn lower(&self, resource: Resource, limit: u64) -> Result<(), ProcessError> {
+        let prev = self.limits[resource as usize].fetch_min(limit, Ordering::Relaxed);
+        if limit > prev {
+            Err(ProcessError::LimitRaised(resource))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Current usage of a counted resource
+    pub fn usage(&self, resource: Resource) -> u64 {
+        self.usage[resource as usize].load(Ordering::Relaxed)
+    }
+
+    /// Adds to the usage of a counted resource, failing without changing anything if it would
+    /// exceed the limit
+    pub fn charge(&self, resource: Resource, amount: u64) -> Result<(), ProcessError> {
+        debug_assert!(matches!(
+            resource,
+            Resource::CommittedMemory | Resource::Handles
+        ));
+
+        let limit = self.limit(resource);
+        self.usage[resource as usize]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                usage.checked_add(amount).filter(|new| *new <= limit)
+            })
+            .map(|_| ())
+            .map_err(|_| ProcessError::LimitExceeded(resource))
+    }
+
+    /// Reverses a previous [charge](Self::charge)
+    pub fn uncharge(&self, resource: Resource, amount: u64) {
+        let prev = self.usage[resource as usize].fetch_sub(amount, Ordering::Relaxed);
+        debug_assert!(prev >= amount, "uncharged more {:?} than charged", resource);
+    }
+
+    pub fn cpu_time_exceeded(&self, cpu_time: &CpuTime) -> bool {
+        cpu_time.total_ns() > self.limit(Resource::CpuTime)
+    }
+}
+
+impl TryFrom<u64> for Resource {
+    type Error = u64;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::CommittedMemory),
+            1 => Ok(Self::Threads),
+            2 => Ok(Self::Handles),
+            3 => Ok(Self::CpuTime),
+            _ => Err(value),
+        }
+    }
+}
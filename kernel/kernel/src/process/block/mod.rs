@@ -1,7 +1,20 @@
+mod filter;
+mod handle;
 mod id;
+mod limits;
 mod process;
 mod thread;
 
-pub use id::new_pid;
-pub use process::{init_kernel_process, ProcessAddressSpace, ProcessPrivilegeLevel, ProcessRef};
-pub use thread::{ThreadProcess, ThreadRef};
+pub use filter::SyscallFilter;
+pub use handle::{Handle, HandleEntry, KernelObject};
+pub use id::{new_pid, Pid};
+pub use limits::{Resource, ResourceLimits, EXIT_CODE_CPU_LIMIT, UNLIMITED};
+pub use process::{
+    init_kernel_process, kernel_process, ExitCode, ProcessAddressSpace, ProcessPrivilegeLevel,
+    ProcessRef, WeakProcessRef,
+};
+pub use thread::{
+    create_kernel_thread, exit_current_process, exit_current_thread, spawn_kernel_thread,
+    spawn_kernel_thread_with_priority, ThreadProcess, ThreadRef, ThreadRunState, UserSegment,
+    WeakThreadRef,
+};
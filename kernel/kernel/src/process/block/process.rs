@@ -1,26 +1,60 @@
 use crate::memory::{
     AddressSpace, AddressSpaceRef, ProcessKernelStacks, ProcessUserStacks, StackGrowth, Stacks,
 };
+use crate::process::block::filter::SyscallFilter;
+use crate::process::block::handle::{Handle, HandleEntry, HandleTable, KernelObject};
 use crate::process::block::id::{OwnedPid, Pid};
+use crate::process::block::limits::{Resource, ResourceLimits};
 use crate::process::block::new_pid;
 use crate::process::block::thread::ThreadRef;
+use crate::process::error::ProcessError;
+use crate::process::registry;
+use crate::scheduler::CpuTime;
 use crate::spinlock::SpinLock;
-use alloc::sync::Arc;
+use crate::sync::WaitQueue;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use common::*;
 use core::cell::RefCell;
 use core::ops::{Deref, DerefMut};
-use memory::{MemoryError, VirtualAddress};
+use core::sync::atomic::{AtomicBool, Ordering};
+use enumflags2::BitFlags;
+use memory::{round_up_to, MapFlags, MapTarget, MemoryError, VirtualAddress, FRAME_SIZE};
 use smallvec::SmallVec;
+use syscall::HandleRights;
 
 #[derive(Clone)]
 pub struct ProcessRef(Arc<ProcessHandle>);
 
+/// Doesn't keep the process alive, used by the global registry
+#[derive(Clone)]
+pub struct WeakProcessRef(Weak<ProcessHandle>);
+
 /// Only immutable references are handed out, fields are protected by refcells/mutexes as needed
 pub struct ProcessHandle {
     /// Set once on creation and never changed
     inner_const: ProcessConstantInner,
     inner_locked: SpinLock<ProcessLockedInner>,
     inner_refcell: RefCell<ProcessInner>,
+
+    /// Woken whenever a child of this process exits
+    child_exited: WaitQueue,
+
+    /// Total of all threads, including those that have exited
+    cpu_time: CpuTime,
+
+    /// Inherited from the parent on creation
+    limits: ResourceLimits,
+
+    /// Log every syscall made by the process, inherited from the parent on creation
+    trace_syscalls: AtomicBool,
+
+    /// Checked before every syscall. Inherited from the parent on creation, tightened by the
+    /// parent's [child_syscall_filter](Self::child_syscall_filter)
+    syscall_filter: SyscallFilter,
+
+    /// Applied on top of [syscall_filter](Self::syscall_filter) to children created afterwards
+    child_syscall_filter: SyscallFilter,
 }
 
 /// Not protected by mutex/refcell, readonly after creation
@@ -38,9 +72,30 @@ pub struct ProcessConstantInner {
 /// Protected by mutex
 pub struct ProcessLockedInner {
     threads: SmallVec<[ThreadRef; 2]>,
+
+    /// User threads that have exited but not yet been joined by [ProcessRef::join_thread]
+    exited_threads: Vec<ThreadRef>,
+
     kernel_stacks: Stacks<ProcessKernelStacks>,
+
+    /// None for the kernel process only. Orphans are reparented to the kernel process
+    parent: Option<WeakProcessRef>,
+
+    /// Strong references so that exited children are kept as zombies until reaped by
+    /// [ProcessRef::wait_for_child]
+    children: Vec<ProcessRef>,
+
+    /// Some once the process has exited and is a zombie
+    exit_code: Option<ExitCode>,
+
+    /// Inheritable handles are copied from the parent on creation. Emptied on exit, as handles to
+    /// threads would otherwise keep the process alive
+    handles: HandleTable,
 }
 
+/// Status passed to exit, returned to the parent when it reaps the process
+pub type ExitCode = u32;
+
 /// Protected by a refcell
 struct ProcessInner {
     /// Not protected by mutex because:
@@ -50,7 +105,7 @@ struct ProcessInner {
     user_stacks: Stacks<ProcessUserStacks>,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum ProcessPrivilegeLevel {
     User,
     Kernel,
@@ -69,23 +124,53 @@ static mut KERNEL_PROCESS: InitializedGlobal<ProcessRef> = InitializedGlobal::un
 /// Must be called once only before other process/thread creation
 pub fn init_kernel_process() {
     let process = ProcessRef::new(
+        None,
         ProcessAddressSpace::Kernel,
         new_pid(),
         ProcessPrivilegeLevel::Kernel,
-    );
+    )
+    .expect("failed to create kernel process");
     unsafe {
         KERNEL_PROCESS.init(process);
     }
 }
 
 impl ProcessRef {
-    pub fn new(addr_space: ProcessAddressSpace, pid: OwnedPid, pl: ProcessPrivilegeLevel) -> Self {
+    /// Parent should only be None for the kernel process. Its limits are inherited, but the new
+    /// process isn't one of its children until passed to [add_child](Self::add_child). Fails if
+    /// the handles inherited from the parent exceed the child's handle limit, which the parent may
+    /// have lowered below its own usage
+    pub fn new(
+        parent: Option<&ProcessRef>,
+        addr_space: ProcessAddressSpace,
+        pid: OwnedPid,
+        pl: ProcessPrivilegeLevel,
+    ) -> Result<Self, ProcessError> {
         let pid_copy = *pid;
         let (addr_space, owns_addr_space) = match addr_space {
             ProcessAddressSpace::Owned(space) => (space, true),
             ProcessAddressSpace::Kernel => (AddressSpace::kernel(), false),
         };
 
+        let handles = parent
+            .map(|parent| parent.inner_locked().handles.inherit())
+            .unwrap_or_default();
+        let limits = parent
+            .map(|parent| parent.limits.inherit())
+            .unwrap_or_default();
+
+        let syscall_filter = match parent {
+            Some(parent) => {
+                let filter = parent.syscall_filter.inherit();
+                let child = &parent.child_syscall_filter;
+                filter.tighten(child.allowed(), child.action());
+                filter
+            }
+            None => SyscallFilter::default(),
+        };
+
+        limits.charge(Resource::Handles, handles.len() as u64)?;
+
         let process = ProcessRef(Arc::new(ProcessHandle {
             inner_const: ProcessConstantInner {
                 addr_space,
@@ -95,16 +180,200 @@ impl ProcessRef {
             },
             inner_locked: SpinLock::new(ProcessLockedInner {
                 threads: SmallVec::new(),
+                exited_threads: Vec::new(),
                 kernel_stacks: Stacks::default(),
+                parent: parent.map(ProcessRef::downgrade),
+                children: Vec::new(),
+                exit_code: None,
+                handles,
             }),
             inner_refcell: RefCell::new(ProcessInner {
                 user_stacks: Stacks::default(),
             }),
+            child_exited: WaitQueue::new(),
+            cpu_time: CpuTime::default(),
+            limits,
+            trace_syscalls: AtomicBool::new(
+                parent
+                    .map(|parent| parent.traces_syscalls())
+                    .unwrap_or(false),
+            ),
+            syscall_filter,
+            child_syscall_filter: SyscallFilter::default(),
         }));
 
         trace!("new process {:?}", pid_copy);
+        registry::register_process(&process);
+
+        Ok(process)
+    }
+
+    /// Makes `child` visible to [wait_for_child](Self::wait_for_child). Called once the child has
+    /// been fully created, so one that fails part way is never waited on
+    pub fn add_child(&self, child: &ProcessRef) {
+        trace!("process {:?} is a child of {:?}", child.pid(), self.pid());
+        self.inner_locked().children.push(child.clone());
+    }
+
+    /// Marks the process as exited with the given code, reparents its children to the kernel
+    /// process and wakes the parent if it is waiting. The process remains a zombie until reaped by
+    /// its parent.
+    ///
+    /// Threads are removed from the process but not stopped, the caller is expected to be the
+    /// last running thread and exit straight after.
+    // TODO kill other threads
+    pub fn exit(&self, exit_code: ExitCode) {
+        assert!(
+            self.privilege_level().is_user(),
+            "kernel process can't exit"
+        );
+
+        let (parent, children, threads, handles) = {
+            let mut inner = self.inner_locked();
+            if let Some(prev) = inner.exit_code {
+                warn!("process {:?} already exited with code {}", self.pid(), prev);
+                return;
+            }
+
+            inner.exit_code = Some(exit_code);
+            let threads = (
+                core::mem::take(&mut inner.threads),
+                core::mem::take(&mut inner.exited_threads),
+            );
+            let children = core::mem::take(&mut inner.children);
+            let handles = core::mem::take(&mut inner.handles);
+            (inner.parent.take(), children, threads, handles)
+        };
+
+        debug!("process {:?} exited with code {}", self.pid(), exit_code);
+
+        // breaks the reference cycle between process and threads, dropped outside of the lock
+        drop(threads);
+        self.limits
+            .uncharge(Resource::Handles, handles.len() as u64);
+        drop(handles);
+
+        let kernel = kernel_process();
+        for child in children {
+            kernel.adopt_orphan(child);
+        }
+
+        match parent.and_then(|weak| weak.upgrade()) {
+            Some(parent) if parent.privilege_level().is_user() => {
+                parent.child_exited.wake_all();
+            }
+            Some(parent) => {
+                // nobody waits on the kernel process, reap immediately
+                let reaped = parent.inner_locked().remove_child(self.pid());
+                drop(reaped);
+            }
+            None => {}
+        }
+    }
 
-        process
+    /// Takes ownership of a child whose parent has exited
+    fn adopt_orphan(&self, child: ProcessRef) {
+        let exited = {
+            let mut inner = child.inner_locked();
+            inner.parent = Some(self.downgrade());
+            inner.exit_code.is_some()
+        };
+
+        if exited {
+            // already a zombie that will never be waited on
+            trace!("reaping orphaned zombie {:?}", child.pid());
+            return;
+        }
+
+        trace!("reparenting {:?} to {:?}", child.pid(), self.pid());
+        self.inner_locked().children.push(child);
+    }
+
+    /// Blocks until a child with the given pid, or any child if None, exits, then reaps it and
+    /// returns its pid and exit code. Errors immediately if there are no matching children
+    pub fn wait_for_child(&self, pid: Option<Pid>) -> Result<(Pid, ExitCode), ProcessError> {
+        let mut result = None;
+        self.child_exited.wait_until(|| {
+            result = self.reap_child(pid);
+            result.is_some()
+        });
+
+        result.unwrap() // only returns once Some
+    }
+
+    /// None if matching children exist but are all still alive
+    fn reap_child(&self, pid: Option<Pid>) -> Option<Result<(Pid, ExitCode), ProcessError>> {
+        let reaped = {
+            let mut inner = self.inner_locked();
+            let mut any_matching = false;
+            let zombie = inner.children.iter().position(|child| {
+                if pid.map(|pid| pid == child.pid()).unwrap_or(true) {
+                    any_matching = true;
+                    child.exit_code().is_some()
+                } else {
+                    false
+                }
+            });
+
+            match zombie {
+                Some(idx) => inner.children.remove(idx),
+                None if any_matching => return None,
+                None => return Some(Err(ProcessError::NoSuchChild(pid))),
+            }
+        };
+
+        // dropped outside of the lock, likely the last reference
+        let exit_code = reaped.exit_code().unwrap();
+        trace!("process {:?} reaped child {:?}", self.pid(), reaped.pid());
+        Some(Ok((reaped.pid(), exit_code)))
+    }
+
+    /// Blocks until the thread with the given tid in this process exits, then forgets it and
+    /// returns its exit value. Must not be the current thread
+    pub fn join_thread(&self, tid: Pid) -> Result<u64, ProcessError> {
+        let thread = {
+            let inner = self.inner_locked();
+            inner
+                .threads
+                .iter()
+                .chain(inner.exited_threads.iter())
+                .find(|thread| thread.tid() == tid)
+                .cloned()
+        };
+
+        let thread = thread.ok_or(ProcessError::NoSuchThread(tid))?;
+        let exit_value = thread.wait_for_exit();
+
+        // dropped outside of the lock, may be the last reference
+        let joined = {
+            let mut inner = self.inner_locked();
+            let idx = inner.exited_threads.iter().position(|t| t.tid() == tid);
+            idx.map(|idx| inner.exited_threads.remove(idx))
+        };
+        trace!("process {:?} joined thread {:?}", self.pid(), tid);
+        drop(joined);
+
+        Ok(exit_value)
+    }
+
+    /// A child that hasn't been reaped yet
+    pub fn child(&self, pid: Pid) -> Option<ProcessRef> {
+        let inner = self.inner_locked();
+        inner
+            .children
+            .iter()
+            .find(|child| child.pid() == pid)
+            .cloned()
+    }
+
+    pub fn downgrade(&self) -> WeakProcessRef {
+        WeakProcessRef(Arc::downgrade(&self.0))
+    }
+}
+
+impl WeakProcessRef {
+    pub fn upgrade(&self) -> Option<ProcessRef> {
+        self.0.upgrade().map(ProcessRef)
     }
 }
 
@@ -139,30 +408,246 @@ impl ProcessHandle {
         self.inner_const.addr_space.borrow()
     }
 
-    /// (user thread stack, kernel thread stack)
+    /// (user thread stack, kernel thread stack). Fails if the process is at its thread limit
     pub fn allocate_new_thread_stacks(
         &self,
-    ) -> Result<(VirtualAddress, VirtualAddress), MemoryError> {
+    ) -> Result<(VirtualAddress, VirtualAddress), ProcessError> {
+        let thread_count = self.inner_locked().threads.len() as u64;
+        if thread_count >= self.limits.limit(Resource::Threads) {
+            return Err(ProcessError::LimitExceeded(Resource::Threads));
+        }
+
         let user = {
             let mut inner = self.inner_refcell.borrow_mut();
-            inner.user_stacks.new_stack()
+            inner.user_stacks.new_stack(&mut self.address_space())
         };
 
-        let kernel = {
-            let mut inner = self.inner_locked.lock();
-            inner.kernel_stacks.new_stack()
-        };
+        let kernel = self.allocate_kernel_thread_stack();
 
         // mutex and refcell dropped asap
 
         let (user, _) = user?;
-        let (kernel, _) = kernel?;
-        Ok((user, kernel))
+        Ok((user, kernel?))
+    }
+
+    /// Kernel stack only, for kernel threads
+    pub fn allocate_kernel_thread_stack(&self) -> Result<VirtualAddress, MemoryError> {
+        let mut inner = self.inner_locked.lock();
+        inner
+            .kernel_stacks
+            .new_stack(&mut self.address_space())
+            .map(|(stack, _)| stack)
+    }
+
+    /// Unmaps the stacks of a thread that will never run again so they can be reused, uncharging
+    /// the committed user stack
+    pub fn free_thread_stacks(
+        &self,
+        kernel_stack: VirtualAddress,
+        user_stack: Option<VirtualAddress>,
+    ) {
+        if let Some(user_stack) = user_stack {
+            let mut inner = self.inner_refcell.borrow_mut();
+            match inner
+                .user_stacks
+                .free_stack(&mut self.address_space(), user_stack)
+            {
+                Ok(frames) => self
+                    .limits
+                    .uncharge(Resource::CommittedMemory, frames * FRAME_SIZE),
+                Err(err) => warn!("failed to free user stack {:?}: {}", user_stack, err),
+            }
+        }
+
+        let mut inner = self.inner_locked();
+        if let Err(err) = inner
+            .kernel_stacks
+            .free_stack(&mut self.address_space(), kernel_stack)
+        {
+            warn!("failed to free kernel stack {:?}: {}", kernel_stack, err);
+        }
     }
 
-    pub fn grow_user_thread_stack(&self, growth: StackGrowth) -> Result<(), MemoryError> {
+    /// Charged against the committed memory limit
+    pub fn grow_user_thread_stack(&self, growth: StackGrowth) -> Result<(), ProcessError> {
+        let size = Stacks::<ProcessUserStacks>::growth_size();
+        self.limits.charge(Resource::CommittedMemory, size)?;
+
         let inner = self.inner_refcell.borrow();
-        inner.user_stacks.grow_stack(growth)
+        let grown = inner
+            .user_stacks
+            .grow_stack(&mut self.address_space(), growth);
+        grown.map_err(|err| {
+            self.limits.uncharge(Resource::CommittedMemory, size);
+            err.into()
+        })
+    }
+
+    /// Maps user memory in this process, returning the page aligned start of the mapping. The
+    /// size is charged against the committed memory limit. The address space doesn't need to be
+    /// current, populate the mapping with [write_user_memory](Self::write_user_memory)
+    pub fn map_user_range(
+        &self,
+        start: VirtualAddress,
+        size: u64,
+        flags: impl Into<BitFlags<MapFlags>>,
+    ) -> Result<VirtualAddress, ProcessError> {
+        let charged = round_up_to(size, FRAME_SIZE);
+        self.limits.charge(Resource::CommittedMemory, charged)?;
+
+        self.address_space()
+            .map_range(start, size, MapTarget::Any, flags.into() | MapFlags::User)
+            .map(|mapped| mapped.address())
+            .map_err(|err| {
+                self.limits.uncharge(Resource::CommittedMemory, charged);
+                err.into()
+            })
+    }
+
+    /// Copies into user memory of this process through the physical identity mapping, without
+    /// switching address space
+    pub fn write_user_memory(&self, addr: VirtualAddress, src: &[u8]) -> Result<(), ProcessError> {
+        self.address_space()
+            .write_bytes(addr, src)
+            .map_err(ProcessError::from)
+    }
+
+    /// Zeroes user memory of this process, as [write_user_memory](Self::write_user_memory)
+    pub fn zero_user_memory(&self, addr: VirtualAddress, len: u64) -> Result<(), ProcessError> {
+        self.address_space()
+            .fill_bytes(addr, len, 0)
+            .map_err(ProcessError::from)
+    }
+
+    pub fn cpu_time(&self) -> &CpuTime {
+        &self.cpu_time
+    }
+
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+
+    pub fn traces_syscalls(&self) -> bool {
+        self.trace_syscalls.load(Ordering::Relaxed)
+    }
+
+    /// Takes effect for this process immediately, and is inherited by children created afterwards
+    pub fn set_trace_syscalls(&self, enabled: bool) {
+        self.trace_syscalls.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn syscall_filter(&self) -> &SyscallFilter {
+        &self.syscall_filter
+    }
+
+    /// Restrictions added to the filter of children created afterwards, but not this process
+    pub fn child_syscall_filter(&self) -> &SyscallFilter {
+        &self.child_syscall_filter
+    }
+
+    /// Current usage of the given resource, to compare against its limit
+    pub fn resource_usage(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Threads => self.inner_locked().threads.len() as u64,
+            Resource::CpuTime => self.cpu_time.total_ns(),
+            Resource::CommittedMemory | Resource::Handles => self.limits.usage(resource),
+        }
+    }
+
+    /// None if the process has a parent that has been destroyed, or is the kernel process
+    pub fn parent(&self) -> Option<ProcessRef> {
+        let inner = self.inner_locked();
+        inner.parent.as_ref().and_then(WeakProcessRef::upgrade)
+    }
+
+    /// Some once the process has exited
+    pub fn exit_code(&self) -> Option<ExitCode> {
+        self.inner_locked().exit_code
+    }
+
+    /// Opens a new handle to the object, charged against the handle limit
+    pub fn open_handle(
+        &self,
+        object: KernelObject,
+        rights: HandleRights,
+    ) -> Result<Handle, ProcessError> {
+        self.limits.charge(Resource::Handles, 1)?;
+
+        let handle = self
+            .inner_locked()
+            .handles
+            .insert(HandleEntry { object, rights });
+        trace!("process {:?} opened {:?}", self.pid(), handle);
+        Ok(handle)
+    }
+
+    /// The object and rights of an open handle, which must have all of the `required` rights
+    pub fn handle_entry(
+        &self,
+        handle: Handle,
+        required: HandleRights,
+    ) -> Result<HandleEntry, ProcessError> {
+        let entry = self
+            .inner_locked()
+            .handles
+            .get(handle)
+            .cloned()
+            .ok_or(ProcessError::BadHandle(handle))?;
+
+        if entry.rights.contains(required) {
+            Ok(entry)
+        } else {
+            Err(ProcessError::HandleRightsMissing {
+                handle,
+                missing: entry.rights.missing(required),
+            })
+        }
+    }
+
+    /// The process an open handle refers to, as [handle_entry](Self::handle_entry)
+    pub fn process_handle(
+        &self,
+        handle: Handle,
+        required: HandleRights,
+    ) -> Result<ProcessRef, ProcessError> {
+        match self.handle_entry(handle, required)?.object {
+            KernelObject::Process(process) => Ok(process),
+            _ => Err(ProcessError::WrongHandleType(handle)),
+        }
+    }
+
+    /// The thread an open handle refers to, as [handle_entry](Self::handle_entry)
+    pub fn thread_handle(
+        &self,
+        handle: Handle,
+        required: HandleRights,
+    ) -> Result<ThreadRef, ProcessError> {
+        match self.handle_entry(handle, required)?.object {
+            KernelObject::Thread(thread) => Ok(thread),
+            _ => Err(ProcessError::WrongHandleType(handle)),
+        }
+    }
+
+    pub fn close_handle(&self, handle: Handle) -> Result<(), ProcessError> {
+        let removed = self.inner_locked().handles.remove(handle);
+        let removed = removed.ok_or(ProcessError::BadHandle(handle))?;
+        self.limits.uncharge(Resource::Handles, 1);
+
+        // dropped outside of the lock, may be the last reference to the object
+        trace!("process {:?} closed {:?}", self.pid(), handle);
+        drop(removed);
+        Ok(())
+    }
+
+    /// Opens another handle to the same object with a subset of the original's rights, which must
+    /// include [HandleRights::DUPLICATE]
+    pub fn duplicate_handle(
+        &self,
+        handle: Handle,
+        rights: HandleRights,
+    ) -> Result<Handle, ProcessError> {
+        let entry = self.handle_entry(handle, HandleRights::DUPLICATE | rights)?;
+        self.open_handle(entry.object, rights)
     }
 }
 
@@ -183,9 +668,29 @@ impl ProcessLockedInner {
         Ok(idx)
     }
 
+    pub fn remove_thread(&mut self, tid: Pid) -> Option<ThreadRef> {
+        let idx = self.threads.iter().position(|t| t.tid() == tid)?;
+        Some(self.threads.remove(idx))
+    }
+
+    /// Live threads only
     pub fn threads(&self) -> impl Iterator<Item = &ThreadRef> + '_ {
         self.threads.iter()
     }
+
+    pub fn add_exited_thread(&mut self, thread: ThreadRef) {
+        self.exited_threads.push(thread);
+    }
+
+    /// Includes zombies that have not yet been reaped
+    pub fn children(&self) -> impl Iterator<Item = &ProcessRef> + '_ {
+        self.children.iter()
+    }
+
+    fn remove_child(&mut self, pid: Pid) -> Option<ProcessRef> {
+        let idx = self.children.iter().position(|p| p.pid() == pid)?;
+        Some(self.children.remove(idx))
+    }
 }
 
 impl ProcessConstantInner {
@@ -207,6 +712,7 @@ impl ProcessPrivilegeLevel {
 impl Drop for ProcessHandle {
     fn drop(&mut self) {
         trace!("dropping process {:?}", self.pid);
+        registry::unregister_process(self.pid());
 
         // TODO kill all threads
         // TODO destroy address space if owned
@@ -1,25 +1,45 @@
 use crate::cpu::CpuState;
 use crate::descriptor_tables::{SEL_USER_CODE, SEL_USER_DATA};
+use crate::io::{FsBase, KernelGsBase, Msr};
 use crate::memory::{AddressSpaceRef, StackGrowth};
-use crate::process::block::id::{OwnedPid, Pid};
-use crate::process::block::process::{kernel_process, ProcessRef};
+use crate::process::apc::UserApc;
+use crate::process::block::id::{new_pid, OwnedPid, Pid};
+use crate::process::block::process::{kernel_process, ExitCode, ProcessRef};
+use crate::process::error::ProcessError;
+use crate::process::{reaper, registry};
+use crate::scheduler::{self, CpuMode, CpuTime, ThreadPriority};
 use crate::spinlock::SpinLock;
-use alloc::sync::Arc;
+use crate::sync::Event;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
 use common::*;
 use core::cell::RefCell;
 use core::ops::Deref;
 use memory::{MemoryError, VirtualAddress};
+use syscall::ApcFrame;
 
 #[derive(Clone)]
 #[repr(transparent)]
 pub struct ThreadRef(Arc<ThreadHandle>);
 
+/// Doesn't keep the thread alive, used by the global registry
+#[derive(Clone)]
+pub struct WeakThreadRef(Weak<ThreadHandle>);
+
 /// Only immutable references are handed out, fields are protected by refcells/mutexes as needed
 pub struct ThreadHandle {
     /// Set once on creation and never changed
     inner_const: ThreadConstantInner,
     inner_locked: SpinLock<ThreadLockedInner>,
     inner_refcell: RefCell<ThreadInner>,
+
+    /// Updated by the scheduler and on every entry to and exit from the kernel
+    cpu_time: CpuTime,
+
+    /// Set once the thread has exited and its exit value is available
+    exited: Event,
 }
 
 /// Not protected by mutex/refcell, readonly after creation
@@ -27,15 +47,57 @@ pub struct ThreadConstantInner {
     process: ProcessRef,
     kernel_stack: VirtualAddress,
 
+    /// Initial user stack pointer, None for kernel threads
+    user_stack: Option<VirtualAddress>,
+
     tid: OwnedPid,
+    name: Option<String>,
 }
 
 /// Protected by mutex
-struct ThreadLockedInner {}
+struct ThreadLockedInner {
+    run_state: ThreadRunState,
+    priority: ThreadPriority,
+
+    /// Temporary boost on top of `priority`, given when woken
+    boost: u8,
+
+    /// Delivered in order on return to userspace, see [crate::process::deliver_pending_apc]
+    user_apcs: VecDeque<UserApc>,
+
+    /// Some once the thread has exited
+    exit_value: Option<u64>,
+}
 
 /// Protected by a refcell
 struct ThreadInner {
     state: ThreadState,
+
+    /// Kernel stack pointer to resume from when switched to by the scheduler
+    saved_kernel_rsp: u64,
+
+    /// Closure to run for threads created by [spawn_kernel_thread], taken on first run
+    kernel_entry: Option<KernelThreadEntry>,
+
+    /// Context to resume on the way out of an `apc_return` syscall
+    apc_return_frame: Option<ApcFrame>,
+}
+
+type KernelThreadEntry = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ThreadRunState {
+    /// In the scheduler's run queue
+    Ready,
+
+    /// Currently executing on the CPU
+    Running,
+
+    /// Waiting to be woken by the scheduler. New threads start in this state
+    Blocked,
+
+    /// Finished and will never run again
+    Exited,
 }
 
 #[derive(Default)]
@@ -61,21 +123,38 @@ struct ThreadState {
 
     rflags: u64,
     rip: u64,
+
+    /// Not restored with the other registers, but saved and loaded from the MSRs on every switch
+    /// to and from the thread
+    fs_base: u64,
+    gs_base: u64,
     // TODO float regs
     // TODO SSE/MMX if necessary
 }
 
+/// Segment registers userspace can set the base of, for thread-local storage
+#[derive(Copy, Clone, Debug)]
+pub enum UserSegment {
+    Fs,
+    Gs,
+}
+
+/// Reserved bit 1 is always set, interrupts are enabled
+const INITIAL_USER_RFLAGS: u64 = 0x202;
+
 pub enum ThreadProcess {
     Process(ProcessRef),
     KernelThread,
 }
 
 impl ThreadRef {
+    /// User thread starting at `entry_point`, with `entry_arg` passed in rcx
     pub fn new(
         process: ThreadProcess,
         tid: OwnedPid,
         entry_point: VirtualAddress,
-    ) -> Result<ThreadRef, MemoryError> {
+        entry_arg: u64,
+    ) -> Result<ThreadRef, ProcessError> {
         let (process, user_stack, kernel_stack) = {
             let proc = match process {
                 ThreadProcess::Process(proc) => proc,
@@ -92,23 +171,109 @@ impl ThreadRef {
             (proc, user, kernel)
         };
 
+        let state = ThreadState {
+            rcx: entry_arg,
+            rsp: user_stack.address(),
+            rip: entry_point.address(),
+            rflags: INITIAL_USER_RFLAGS,
+            ..ThreadState::default()
+        };
+
+        Ok(Self::with_stacks(
+            process,
+            tid,
+            None,
+            ThreadPriority::default(),
+            kernel_stack,
+            Some(user_stack),
+            state,
+            None,
+        ))
+    }
+
+    /// Kernel thread that runs the given closure on its kernel stack, then exits
+    fn new_kernel(
+        tid: OwnedPid,
+        name: String,
+        priority: ThreadPriority,
+        entry: KernelThreadEntry,
+    ) -> Result<ThreadRef, MemoryError> {
+        let process = kernel_process();
+        let kernel_stack = process.allocate_kernel_thread_stack()?;
+        trace!(
+            "kernel thread {:?} ({:?}) has stack at {:?}",
+            tid,
+            name,
+            kernel_stack
+        );
+
+        // only the kernel stack is used, the state is never restored
+        let state = ThreadState {
+            rsp: kernel_stack.address(),
+            ..ThreadState::default()
+        };
+
+        Ok(Self::with_stacks(
+            process,
+            tid,
+            Some(name),
+            priority,
+            kernel_stack,
+            None,
+            state,
+            Some(entry),
+        ))
+    }
+
+    /// Creates the thread and registers it with its process and the global registry
+    #[allow(clippy::too_many_arguments)]
+    fn with_stacks(
+        process: ProcessRef,
+        tid: OwnedPid,
+        name: Option<String>,
+        priority: ThreadPriority,
+        kernel_stack: VirtualAddress,
+        user_stack: Option<VirtualAddress>,
+        state: ThreadState,
+        kernel_entry: Option<KernelThreadEntry>,
+    ) -> ThreadRef {
+        let first_entry = if kernel_entry.is_some() {
+            kernel_thread_entry
+        } else {
+            thread_entry
+        };
+
+        // safety: kernel stack has just been allocated
+        let saved_kernel_rsp = unsafe { scheduler::initial_context(kernel_stack, first_entry) };
+
         let tid_copy = *tid;
         let thread = ThreadRef(Arc::new(ThreadHandle {
             inner_const: ThreadConstantInner {
                 process: process.clone(),
                 kernel_stack,
+                user_stack,
                 tid,
+                name,
             },
-            inner_locked: SpinLock::new(ThreadLockedInner {}),
+            inner_locked: SpinLock::new(ThreadLockedInner {
+                run_state: ThreadRunState::Blocked,
+                priority,
+                boost: 0,
+                user_apcs: VecDeque::new(),
+                exit_value: None,
+            }),
             inner_refcell: RefCell::new(ThreadInner {
-                state: ThreadState {
-                    rsp: user_stack.address(),
-                    rip: entry_point.address(),
-                    ..ThreadState::default()
-                },
+                state,
+                saved_kernel_rsp,
+                kernel_entry,
+                apc_return_frame: None,
             }),
+            cpu_time: CpuTime::default(),
+            exited: Event::new(),
         }));
 
+        registry::register_thread(&thread);
+
         // register with process
         let idx = {
             let mut inner = process.inner_locked();
@@ -122,17 +287,102 @@ impl ThreadRef {
             idx
         );
 
-        Ok(thread)
+        thread
     }
 
-    pub unsafe fn switch_to(&self) -> ! {
-        CpuState::update_current_thread(self.clone());
+    pub fn grow_user_stack(&self, growth: StackGrowth) -> Result<(), ProcessError> {
+        self.process.grow_user_thread_stack(growth)
+    }
 
-        self.0.run_now()
+    pub fn downgrade(&self) -> WeakThreadRef {
+        WeakThreadRef(Arc::downgrade(&self.0))
     }
+}
 
-    pub fn grow_user_stack(&self, growth: StackGrowth) -> Result<(), MemoryError> {
-        self.process.grow_user_thread_stack(growth)
+/// Spawns a new kernel thread in the shared kernel process that runs `f` on its own kernel stack
+/// with interrupts enabled. The thread exits when `f` returns.
+pub fn spawn_kernel_thread<F>(name: &str, f: F) -> Result<ThreadRef, MemoryError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_kernel_thread_with_priority(name, ThreadPriority::default(), f)
+}
+
+/// As [spawn_kernel_thread], e.g. for realtime driver threads
+pub fn spawn_kernel_thread_with_priority<F>(
+    name: &str,
+    priority: ThreadPriority,
+    f: F,
+) -> Result<ThreadRef, MemoryError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = create_kernel_thread(name, priority, f)?;
+    scheduler::wake(&thread);
+    Ok(thread)
+}
+
+/// As [spawn_kernel_thread_with_priority] but the thread is left blocked, to be woken by the caller
+pub fn create_kernel_thread<F>(
+    name: &str,
+    priority: ThreadPriority,
+    f: F,
+) -> Result<ThreadRef, MemoryError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    ThreadRef::new_kernel(new_pid(), String::from(name), priority, Box::new(f))
+}
+
+/// Removes the current thread from its process and switches away from it for good, waking any
+/// threads joining it. If it was the last thread in a user process, the process exits with code 0,
+/// otherwise a user thread is kept until joined to hold `exit_value`
+pub fn exit_current_thread(exit_value: u64) -> ! {
+    {
+        // safety: only called from thread context
+        let thread = unsafe { CpuState::current_thread() };
+        let is_user = thread.process.privilege_level().is_user();
+        let (removed, now_empty) = {
+            let mut inner = thread.process.inner_locked();
+            let removed = inner.remove_thread(thread.tid());
+            let now_empty = inner.threads().next().is_none();
+            debug_assert!(removed.is_some(), "exiting thread not in its process");
+            match removed {
+                Some(removed) if is_user && !now_empty => {
+                    inner.add_exited_thread(removed);
+                    (None, now_empty)
+                }
+                removed => (removed, now_empty),
+            }
+        };
+        drop(removed);
+
+        thread.inner_locked.lock().exit_value = Some(exit_value);
+        thread.exited.set();
+
+        if now_empty && is_user {
+            thread.process.exit(0);
+        }
+    }
+
+    scheduler::exit_current()
+}
+
+/// Exits the current process with the given code, then switches away from the current thread for
+/// good
+pub fn exit_current_process(exit_code: ExitCode) -> ! {
+    {
+        // safety: only called from thread context
+        let thread = unsafe { CpuState::current_thread() };
+        thread.process.exit(exit_code);
+    }
+
+    scheduler::exit_current()
+}
+
+impl WeakThreadRef {
+    pub fn upgrade(&self) -> Option<ThreadRef> {
+        self.0.upgrade().map(ThreadRef)
     }
 }
 
@@ -157,15 +407,31 @@ impl ThreadConstantInner {
         *self.tid
     }
 
+    pub fn process(&self) -> &ProcessRef {
+        &self.process
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn kernel_stack(&self) -> VirtualAddress {
         self.kernel_stack
     }
+
+    /// Top of the user stack the thread started with, None for kernel threads
+    pub fn user_stack(&self) -> Option<VirtualAddress> {
+        self.user_stack
+    }
 }
 
 impl Drop for ThreadHandle {
     fn drop(&mut self) {
         trace!("dropping thread {:?}", self.tid);
-        // TODO unmap kernel stack
+        registry::unregister_thread(self.tid());
+
+        // may still be running on the kernel stack, so it's freed later
+        reaper::queue(self.process.clone(), self.kernel_stack, self.user_stack);
     }
 }
 
@@ -187,6 +453,40 @@ const OFFSET_R15: usize = memoffset::offset_of!(ThreadState, r15);
 const OFFSET_RIP: usize = memoffset::offset_of!(ThreadState, rip);
 const OFFSET_RSP: usize = memoffset::offset_of!(ThreadState, rsp);
 const OFFSET_RFLAGS: usize = memoffset::offset_of!(ThreadState, rflags);
+const OFFSET_FS_BASE: usize = memoffset::offset_of!(ThreadState, fs_base);
+
+/// First code run by a new thread, when the scheduler switches to it for the first time
+extern "C" fn thread_entry() -> ! {
+    // safety: scheduler has just switched to this thread
+    let thread = unsafe { CpuState::current_thread() };
+
+    // the cpu state holds a reference for as long as this thread is running, and nothing on this
+    // stack is ever dropped after jumping to the thread
+    let handle = &*thread as *const ThreadHandle;
+    drop(thread);
+
+    unsafe { (*handle).run_now() }
+}
+
+/// First code run by a kernel thread created by [spawn_kernel_thread]
+extern "C" fn kernel_thread_entry() -> ! {
+    let entry = {
+        // safety: scheduler has just switched to this thread
+        let thread = unsafe { CpuState::current_thread() };
+        let mut inner = thread.inner_refcell.borrow_mut();
+        inner
+            .kernel_entry
+            .take()
+            .expect("kernel thread has no entry")
+    };
+
+    // threads are switched to for the first time with interrupts disabled
+    unsafe { asm!("sti") };
+
+    entry();
+
+    exit_current_thread(0)
+}
 
 impl ThreadHandle {
     unsafe fn run_now(&self) -> ! {
@@ -195,6 +495,7 @@ impl ThreadHandle {
         let state = self.thread_state();
 
         if self.is_user() {
+            scheduler::charge_current(CpuMode::Kernel);
             Self::restore_user_space(state)
         } else {
             Self::restore_kernel_space(state)
@@ -260,13 +561,20 @@ impl ThreadHandle {
             "mov bx, {ds_user}",
             "mov ds, bx",
             "mov es, bx",
-            "mov fs, bx",
-            "mov gs, bx", // ss handled by iret
+            "mov fs, bx", // gs is swapped below, ss handled by iret
+
+            // loading fs clears its base, so restore the thread's
+            "mov rbx, rax",
+            "mov ecx, {msr_fs_base}",
+            "mov eax, [rbx + {offset_fs_base}]",
+            "mov edx, [rbx + {offset_fs_base} + 4]",
+            "wrmsr",
+            "mov rax, rbx",
 
             // user stack in ds
             "push {ds_user}",
             "push [rax + {offset_rsp}]", // user rsp
-            "pushf", // eflags
+            "push [rax + {offset_rflags}]", // rflags
 
             // user rip in cs
             "push {cs_user}",
@@ -296,18 +604,23 @@ impl ThreadHandle {
             // restore rax
             "pop rax",
 
+            // cpu state back in KernelGSbase
+            "swapgs",
+
             // jmp to rip
             "iretq",
 
             ds_user = const SEL_USER_DATA,
             cs_user = const SEL_USER_CODE,
+            msr_fs_base = const FsBase::MSR,
 
             offset_r15 = const OFFSET_R15, offset_r14 = const OFFSET_R14, offset_r13 = const OFFSET_R13,
             offset_r12 = const OFFSET_R12, offset_r11 = const OFFSET_R11, offset_r10 = const OFFSET_R10,
             offset_r09 = const OFFSET_R09, offset_r08 = const OFFSET_R08, offset_rsi = const OFFSET_RSI,
             offset_rdi = const OFFSET_RDI, offset_rdx = const OFFSET_RDX, offset_rcx = const OFFSET_RCX,
             offset_rbx = const OFFSET_RBX, offset_rax = const OFFSET_RAX, offset_rbp = const OFFSET_RBP,
-            offset_rsp = const OFFSET_RSP, offset_rip = const OFFSET_RIP,
+            offset_rsp = const OFFSET_RSP, offset_rip = const OFFSET_RIP, offset_rflags = const OFFSET_RFLAGS,
+            offset_fs_base = const OFFSET_FS_BASE,
 
             in("rax") state,
 
@@ -327,7 +640,134 @@ impl ThreadHandle {
         self.inner_const.process.address_space()
     }
 
+    pub fn cpu_time(&self) -> &CpuTime {
+        &self.cpu_time
+    }
+
     pub fn is_user(&self) -> bool {
         self.inner_const.process.privilege_level().is_user()
     }
+
+    pub fn run_state(&self) -> ThreadRunState {
+        self.inner_locked.lock().run_state
+    }
+
+    /// Should only be changed by the scheduler
+    pub fn set_run_state(&self, state: ThreadRunState) {
+        self.inner_locked.lock().run_state = state;
+    }
+
+    pub fn priority(&self) -> ThreadPriority {
+        self.inner_locked.lock().priority
+    }
+
+    /// Should only be changed by the scheduler, see [scheduler::set_priority]
+    pub fn set_priority(&self, priority: ThreadPriority) {
+        self.inner_locked.lock().priority = priority;
+    }
+
+    pub fn boost(&self) -> u8 {
+        self.inner_locked.lock().boost
+    }
+
+    /// Should only be changed by the scheduler
+    pub fn set_boost(&self, boost: u8) {
+        self.inner_locked.lock().boost = boost;
+    }
+
+    /// Run queue index including any boost, higher runs first
+    pub fn effective_priority(&self) -> usize {
+        let inner = self.inner_locked.lock();
+        inner.priority.effective(inner.boost)
+    }
+
+    /// Queues a routine to run on this user thread the next time it returns to userspace, see
+    /// [crate::process::deliver_pending_apc]
+    pub fn queue_user_apc(&self, apc: UserApc) {
+        debug_assert!(self.is_user(), "APC queued to kernel thread");
+        self.inner_locked.lock().user_apcs.push_back(apc);
+    }
+
+    pub fn take_user_apc(&self) -> Option<UserApc> {
+        self.inner_locked.lock().user_apcs.pop_front()
+    }
+
+    pub fn has_user_apc(&self) -> bool {
+        !self.inner_locked.lock().user_apcs.is_empty()
+    }
+
+    pub fn set_apc_return_frame(&self, frame: ApcFrame) {
+        self.inner_refcell.borrow_mut().apc_return_frame = Some(frame);
+    }
+
+    pub fn take_apc_return_frame(&self) -> Option<ApcFrame> {
+        self.inner_refcell.borrow_mut().apc_return_frame.take()
+    }
+
+    pub fn has_apc_return_frame(&self) -> bool {
+        self.inner_refcell.borrow().apc_return_frame.is_some()
+    }
+
+    /// Blocks until the thread exits, then returns its exit value. Must not be the current thread
+    pub fn wait_for_exit(&self) -> u64 {
+        self.exited.wait();
+        self.inner_locked
+            .lock()
+            .exit_value
+            .expect("exited thread has no exit value")
+    }
+
+    /// Saves the user FS and GS base from the MSRs into the thread state. User GS base is in
+    /// KernelGSbase while in the kernel.
+    ///
+    /// # Safety
+    /// Must be the current thread, and the user GS base must have been swapped out
+    pub unsafe fn save_user_segment_bases(&self) {
+        let mut inner = self.inner_refcell.borrow_mut();
+        inner.state.fs_base = FsBase::load().value();
+        inner.state.gs_base = KernelGsBase::load().value();
+    }
+
+    /// Loads the user FS and GS base from the thread state into the MSRs, the GS base is swapped
+    /// in on return to userspace.
+    ///
+    /// # Safety
+    /// Must be about to become the current thread
+    pub unsafe fn load_user_segment_bases(&self) {
+        let inner = self.inner_refcell.borrow();
+        FsBase::with_value(inner.state.fs_base).store_quietly();
+        KernelGsBase::with_value(inner.state.gs_base).store_quietly();
+    }
+
+    /// Sets the user FS or GS base, taking effect on return to userspace. Base must be a
+    /// canonical address.
+    ///
+    /// # Safety
+    /// Must be the current thread, and the user GS base must have been swapped out
+    pub unsafe fn set_user_segment_base(&self, segment: UserSegment, base: u64) {
+        let mut inner = self.inner_refcell.borrow_mut();
+        match segment {
+            UserSegment::Fs => {
+                inner.state.fs_base = base;
+                FsBase::with_value(base).store_quietly();
+            }
+            UserSegment::Gs => {
+                inner.state.gs_base = base;
+                KernelGsBase::with_value(base).store_quietly();
+            }
+        }
+    }
+
+    pub fn saved_kernel_rsp(&self) -> u64 {
+        self.inner_refcell.borrow().saved_kernel_rsp
+    }
+
+    /// Written to by the scheduler when switching away from this thread
+    pub fn saved_kernel_rsp_ptr(&self) -> *mut u64 {
+        // ensure we can access it by borrowing first
+        let _inner = self.inner_refcell.borrow_mut();
+
+        let ptr = self.inner_refcell.as_ptr() as *const ThreadInner;
+        memoffset::raw_field!(ptr, ThreadInner, saved_kernel_rsp) as *mut u64
+    }
 }
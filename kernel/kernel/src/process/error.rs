@@ -1,5 +1,8 @@
+use crate::process::{Handle, Pid, Resource};
 use common::Display;
+use memory::MemoryError;
 use pe::PeError;
+use syscall::{HandleRights, SyscallError};
 
 #[derive(Debug, Display)]
 pub enum ProcessError {
@@ -17,6 +20,36 @@ pub enum ProcessError {
 
     /// Image has no entry point
     NoEntrypoint,
+
+    /// No executable image with the given name
+    NoSuchImage,
+
+    /// No child process matching {0:?}
+    NoSuchChild(Option<Pid>),
+
+    /// No thread {0:?} in the process
+    NoSuchThread(Pid),
+
+    /// Memory error: {0}
+    Memory(MemoryError),
+
+    /// Resource limit for {0:?} exceeded
+    LimitExceeded(Resource),
+
+    /// Resource limit for {0:?} can only be lowered
+    LimitRaised(Resource),
+
+    /// {0:?} is not open
+    BadHandle(Handle),
+
+    /// {0:?} refers to the wrong type of object
+    WrongHandleType(Handle),
+
+    /// {handle:?} lacks the {missing:?} rights
+    HandleRightsMissing {
+        handle: Handle,
+        missing: HandleRights,
+    },
 }
 
 impl From<pe::PeError> for ProcessError {
@@ -24,3 +57,37 @@ impl From<pe::PeError> for ProcessError {
         Self::Pe(err)
     }
 }
+
+impl From<MemoryError> for ProcessError {
+    fn from(err: MemoryError) -> Self {
+        Self::Memory(err)
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(err: ProcessError) -> Self {
+        use ProcessError::*;
+        match err {
+            Pe(_) | NoEntrypoint | LengthMismatch { .. } => SyscallError::InvalidImage,
+            BadVirtualAddress(_) => SyscallError::BadAddress,
+            RelocationUnsupported => SyscallError::NotImplemented,
+            NoSuchImage | NoSuchChild(_) | NoSuchThread(_) => SyscallError::NotFound,
+            Memory(err) => memory_syscall_error(&err),
+            LimitExceeded(_) => SyscallError::LimitExceeded,
+            LimitRaised(_) => SyscallError::PermissionDenied,
+            BadHandle(_) | WrongHandleType(_) => SyscallError::BadHandle,
+            HandleRightsMissing { .. } => SyscallError::PermissionDenied,
+        }
+    }
+}
+
+/// [MemoryError] is defined in another crate so can't be converted with [From]
+fn memory_syscall_error(err: &MemoryError) -> SyscallError {
+    use MemoryError::*;
+    match err {
+        NoFrame | NoPremappedFrame | NoContiguousVirtualRegion(..) => SyscallError::OutOfMemory,
+        NotMapped(_) => SyscallError::BadAddress,
+        AlreadyMapped(_) => SyscallError::AlreadyExists,
+        _ => SyscallError::UnknownError,
+    }
+}
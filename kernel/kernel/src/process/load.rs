@@ -1,123 +1,137 @@
-use crate::memory::AddressSpace;
+use crate::memory::{AddressSpace, ProcessUserStacks, Stacks};
 use crate::process::block::{
     new_pid, ProcessAddressSpace, ProcessPrivilegeLevel, ProcessRef, ThreadProcess,
 };
 use crate::process::error::ProcessError;
+use alloc::vec;
 
 use crate::process::ThreadRef;
-use common::{
-    anyhow::{self, anyhow, Error},
-    *,
-};
-use memory::{round_up_to, MapFlags, MapTarget, VirtualAddress, FRAME_SIZE};
+use crate::shared_data;
+use common::*;
+use memory::{round_up_to, MapFlags, VirtualAddress, FRAME_SIZE};
 use pe::{Address, Pe, PeError};
-
-// temporary
-const NOP_EXE: &[u8] = include_bytes!("../../../../userspace/syscall.exe");
-
-// TODO need to configure via args:
-//  * user vs kernel
-//  * exe mapped address/pointer/reference
+use syscall::ProcessParameters;
+
+/// Executables that can be spawned by name, until there is a filesystem
+// TODO load from the filesystem
+static EMBEDDED_IMAGES: &[(&str, &[u8])] = &[
+    (
+        "syscall.exe",
+        include_bytes!("../../../../userspace/syscall.exe"),
+    ),
+    ("nop.exe", include_bytes!("../../../../userspace/nop.exe")),
+];
+
+fn embedded_image(name: &str) -> Option<&'static [u8]> {
+    EMBEDDED_IMAGES
+        .iter()
+        .find(|(image_name, _)| *image_name == name)
+        .map(|(_, image)| *image)
+}
 
 // TODO ensure address space and/or mappings are freed/unmapped on error with e.g. a Bomb guard
-/// For testing only
-/// Switches to new address space
-pub fn experiment_new_process() -> anyhow::Result<ProcessRef> {
-    let image = NOP_EXE;
-
-    // allocate and load new addr space for PE
-    // TODO not for kernel threads
-    let mut address_space = AddressSpace::new().map_err(Error::msg)?;
-    unsafe {
-        address_space.load_unconditionally();
-    }
+/// Creates a user process as a child of `parent`, running the named executable from the embedded
+/// image set. Returns the process and its main thread, which is left blocked to be woken by the
+/// caller.
+///
+/// The main thread is passed a pointer to a [ProcessParameters] block holding the given command
+/// line and environment variables
+pub fn spawn_process(
+    parent: &ProcessRef,
+    image_name: &str,
+    command_line: &str,
+    environment: &[(&str, &str)],
+) -> Result<(ProcessRef, ThreadRef), ProcessError> {
+    let image = embedded_image(image_name).ok_or(ProcessError::NoSuchImage)?;
+
+    // allocate new addr space for PE, populated without switching to it
+    let mut address_space = AddressSpace::new()?;
+    shared_data::map_into(&mut address_space)?;
 
     // parse PE
     // TODO parse in new userspace process in its own address space
     //  * PE loader needs to be available as a userspace dll
     //      * not necessarily on filesystem
     //  * PE loader needs to replace itself with the new process
-    let pe = pe::Pe::from_buffer(image).map_err(Error::msg)?;
+    let pe = pe::Pe::from_buffer(image)?;
 
     // check image can be mapped at preferred base without relocating
-    let (image_base, pages_needed, entry_point_rva) =
-        extract_optional_header(&pe).map_err(Error::msg)?;
-    let free_base = address_space
-        .find_free_space(image_base, pages_needed)
-        .map_err(Error::msg)?;
+    let (image_base, pages_needed, entry_point_rva) = extract_optional_header(&pe)?;
+    let free_base = address_space.find_free_space(image_base, pages_needed)?;
 
     if free_base != image_base {
         // TODO support relocations
-        return Err(anyhow!(ProcessError::RelocationUnsupported));
+        return Err(ProcessError::RelocationUnsupported);
     }
 
     debug!("mapping image at preferred base address {:?}", image_base);
 
-    let entry_point_rva = entry_point_rva
-        .ok_or(ProcessError::NoEntrypoint)
-        .map_err(Error::msg)?;
+    let entry_point_rva = entry_point_rva.ok_or(ProcessError::NoEntrypoint)?;
+
+    // created before mapping so memory is charged against its limits
+    let proc = ProcessRef::new(
+        Some(parent),
+        ProcessAddressSpace::Owned(address_space),
+        new_pid(),
+        ProcessPrivilegeLevel::User,
+    )?;
 
     let length = pages_needed * FRAME_SIZE as usize;
-    let mut mapped = {
-        // map as RWX and userspace (TODO depends on options)
-        address_space
-            .map_range(
-                image_base,
-                length as u64,
-                MapTarget::Any,
-                MapFlags::Writeable | MapFlags::Executable | MapFlags::User,
-            )
-            .map_err(Error::msg)?
-    };
-    let mapped_slice = mapped.write().unwrap(); // mapped as writeable
+
+    // map as RWX and userspace (TODO depends on options)
+    proc.map_user_range(
+        image_base,
+        length as u64,
+        MapFlags::Writeable | MapFlags::Executable,
+    )?;
 
     trace!("mapped {:#x} bytes for image", length);
 
     // copy headers
     // TODO mmap to exe file instead of copying manually
     {
-        let headers = pe.headers().map_err(Error::msg)?;
-        let dst = mapped_slice
-            .get_mut(..headers.len())
-            .ok_or(ProcessError::LengthMismatch {
+        let headers = pe.headers()?;
+        if headers.len() > length {
+            return Err(ProcessError::LengthMismatch {
                 src: headers.len(),
                 dst: length,
-            })
-            .map_err(Error::msg)?;
+            });
+        }
 
-        dst.copy_from_slice(headers);
+        proc.write_user_memory(image_base, headers)?;
 
         // TODO update header permissions as ro
     }
 
     // copy sections
-    for section in pe.sections().map_err(Error::msg)? {
-        let section = match section.and_then(|s| s.as_mappable()).map_err(Error::msg)? {
+    for section in pe.sections()? {
+        let section = match section.and_then(|s| s.as_mappable())? {
             Some(mappable) => mappable,
             None => continue,
         };
 
         debug!("mapping section {:?}", section);
 
-        let dst = {
-            let start = section.virtual_address.into_usize();
-
-            mapped_slice
-                .get_mut(start..start + section.virtual_size)
-                .ok_or(PeError::VirtualSliceOutOfBounds {
-                    what: "section",
-                    addr: section.virtual_address,
-                    length,
-                })
-                .map_err(Error::msg)?
-        };
+        let start = section.virtual_address.into_usize();
+        if start + section.virtual_size > length {
+            return Err(PeError::VirtualSliceOutOfBounds {
+                what: "section",
+                addr: section.virtual_address,
+                length,
+            }
+            .into());
+        }
+
+        let dst = image_base + start as u64;
 
         // copy raw data if any
         let zero_from = if let Some((size, offset)) = section.raw_data {
-            let src = pe.slice(offset, size).map_err(Error::msg)?;
-            let src = &src[..dst.len()]; // shorten to virtual size
-            dst.copy_from_slice(src); // TODO panics if virtual size > raw size
-            dst.len()
+            let src = pe.slice(offset, size)?;
+            // shorten to virtual size
+            // TODO panics if virtual size > raw size
+            let src = &src[..section.virtual_size];
+            proc.write_user_memory(dst, src)?;
+            src.len()
         } else {
             0
         };
@@ -126,8 +140,7 @@ pub fn experiment_new_process() -> anyhow::Result<ProcessRef> {
         let zeros = section.virtual_size - zero_from;
         if zeros > 0 {
             trace!("zeroing {:#x} bytes in section {}", zeros, section.name);
-            let slice = &mut dst[zero_from..section.virtual_size];
-            slice.fill(0);
+            proc.zero_user_memory(dst + zero_from as u64, zeros as u64)?;
         }
 
         // TODO protect sections properly
@@ -135,18 +148,87 @@ pub fn experiment_new_process() -> anyhow::Result<ProcessRef> {
 
     let entry_point = image_base + entry_point_rva;
 
+    // map parameter block after the image, filled in once the main thread exists
+    let params_len = parameters_block_len(command_line, environment);
+    let params_addr = {
+        let pages = round_up_to(params_len as u64, FRAME_SIZE) / FRAME_SIZE;
+        let base = proc
+            .address_space()
+            .find_free_space(image_base + length as u64, pages as usize)?;
+
+        proc.map_user_range(base, params_len as u64, MapFlags::Writeable)?
+    };
+    trace!("mapped process parameters at {:?}", params_addr);
+
     // TODO allocate heap
     // TODO respect PE requested heap+stack commit/reserve
     // TODO flush instruction cache?
 
-    let proc = ProcessRef::new(
-        ProcessAddressSpace::Owned(address_space),
+    let thread = ThreadRef::new(
+        ThreadProcess::Process(proc.clone()),
         new_pid(),
-        ProcessPrivilegeLevel::User,
-    );
-    let _thread = ThreadRef::new(ThreadProcess::Process(proc.clone()), new_pid(), entry_point)
-        .map_err(Error::msg)?;
-    Ok(proc)
+        entry_point,
+        params_addr.address(),
+    )?;
+
+    // thread doesn't run until woken so the block can be filled in afterwards
+    let stack_top = thread.user_stack().unwrap(); // user thread
+    let params = ProcessParameters {
+        size: core::mem::size_of::<ProcessParameters>() as u64,
+        pid: u64::from(proc.pid()),
+        image_base: image_base.address(),
+        stack_top: stack_top.address(),
+        stack_limit: Stacks::<ProcessUserStacks>::stack_limit(stack_top).address(),
+        command_line: 0,
+        command_line_len: 0,
+        environment: 0,
+        environment_len: 0,
+    };
+    let mut block = vec![0u8; params_len];
+    write_parameters_block(params, command_line, environment, params_addr, &mut block);
+    if let Err(err) = proc.write_user_memory(params_addr, &block) {
+        // breaks the reference cycle between process and thread, dropped outside of the lock
+        let removed = proc.inner_locked().remove_thread(thread.tid());
+        drop(removed);
+        return Err(err);
+    }
+
+    // nothing else can fail, so the parent can see it now
+    parent.add_child(&proc);
+    Ok((proc, thread))
+}
+
+fn parameters_block_len(command_line: &str, environment: &[(&str, &str)]) -> usize {
+    core::mem::size_of::<ProcessParameters>()
+        + command_line.len()
+        + syscall::environment_block_len(environment)
+}
+
+/// Writes the block followed by the command line and environment into `dst`, which will be copied
+/// to `dst_addr` in the new process
+fn write_parameters_block(
+    mut params: ProcessParameters,
+    command_line: &str,
+    environment: &[(&str, &str)],
+    dst_addr: VirtualAddress,
+    dst: &mut [u8],
+) {
+    let (header, strings) = dst.split_at_mut(core::mem::size_of::<ProcessParameters>());
+    let strings_addr = dst_addr + header.len() as u64;
+
+    let (cmdline_dst, env_dst) = strings.split_at_mut(command_line.len());
+    cmdline_dst.copy_from_slice(command_line.as_bytes());
+    let env_len = syscall::encode_environment_block(environment, env_dst);
+
+    params.command_line = strings_addr.address();
+    params.command_line_len = command_line.len() as u64;
+    params.environment = (strings_addr + command_line.len() as u64).address();
+    params.environment_len = env_len as u64;
+
+    // safety: header is the size of the struct
+    unsafe {
+        core::ptr::write_unaligned(header.as_mut_ptr() as *mut ProcessParameters, params);
+    }
 }
 
 /// (image base, size of image in 4k pages, entrypoint RVA)
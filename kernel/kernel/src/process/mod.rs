@@ -1,6 +1,28 @@
+mod apc;
 mod block;
 mod error;
 mod load;
+mod reaper;
+mod registry;
 
-pub use block::{init_kernel_process, ProcessRef, ThreadRef};
-pub use load::experiment_new_process;
+pub use apc::{
+    begin_apc_return, deliver_pending_apc, finish_user_return, needs_full_user_return, UserApc,
+};
+pub use block::{
+    create_kernel_thread, exit_current_process, exit_current_thread, kernel_process, new_pid,
+    spawn_kernel_thread, spawn_kernel_thread_with_priority, ExitCode, Handle, HandleEntry,
+    KernelObject, Pid, ProcessRef, Resource, ResourceLimits, SyscallFilter, ThreadProcess,
+    ThreadRef, ThreadRunState, UserSegment, WeakProcessRef, WeakThreadRef, EXIT_CODE_CPU_LIMIT,
+    UNLIMITED,
+};
+pub use error::ProcessError;
+pub use load::spawn_process;
+pub use reaper::spawn_reaper;
+pub use registry::{log_all, process_by_pid, processes, thread_by_tid, threads};
+
+/// Must be called once only before other process/thread creation
+pub fn init() {
+    registry::init();
+    reaper::init();
+    block::init_kernel_process();
+}
@@ -0,0 +1,86 @@
+//! Frees the stacks of dead threads from a kernel thread of its own.
+//!
+//! The last reference to a thread can be dropped anywhere, including during a context switch while
+//! still running on the thread's kernel stack, or while its process is locked. Its destructor only
+//! queues the stacks here, and they're freed later with no locks held.
+
+use crate::irq::InterruptsDisabled;
+use crate::process::block::{spawn_kernel_thread, ProcessRef};
+use crate::spinlock::SpinLock;
+use crate::sync::WaitQueue;
+use alloc::vec::Vec;
+use common::*;
+use memory::VirtualAddress;
+
+/// Stacks of a thread that will never run again
+struct DeadThread {
+    process: ProcessRef,
+    kernel_stack: VirtualAddress,
+    user_stack: Option<VirtualAddress>,
+}
+
+/// The queue lock is only taken with interrupts disabled, so queueing can't deadlock with the
+/// reaper however the dropping thread got there
+struct Reaper {
+    dead: SpinLock<Vec<DeadThread>>,
+    wait: WaitQueue,
+}
+
+static mut REAPER: InitializedGlobal<Reaper> = InitializedGlobal::uninit();
+
+/// Must be called once only before any thread is dropped
+pub fn init() {
+    let reaper = Reaper {
+        dead: SpinLock::new(Vec::new()),
+        wait: WaitQueue::new(),
+    };
+
+    unsafe {
+        REAPER.init(reaper);
+    }
+}
+
+fn reaper() -> &'static Reaper {
+    unsafe { REAPER.get() }
+}
+
+/// Must be called once only after the scheduler is initialised. Threads dropped before then are
+/// reaped once it starts
+pub fn spawn_reaper() {
+    spawn_kernel_thread("reaper", reap_forever).expect("failed to spawn reaper thread");
+}
+
+/// Called from the thread destructor
+pub fn queue(
+    process: ProcessRef,
+    kernel_stack: VirtualAddress,
+    user_stack: Option<VirtualAddress>,
+) {
+    let _irq = InterruptsDisabled::acquire();
+
+    let reaper = reaper();
+    reaper.dead.lock().push(DeadThread {
+        process,
+        kernel_stack,
+        user_stack,
+    });
+    reaper.wait.wake_one();
+}
+
+fn reap_forever() {
+    let reaper = reaper();
+    loop {
+        let dead = {
+            let _irq = InterruptsDisabled::acquire();
+            reaper.wait.wait_until(|| !reaper.dead.lock().is_empty());
+            core::mem::take(&mut *reaper.dead.lock())
+        };
+
+        trace!("reaping {} dead threads", dead.len());
+        for thread in dead {
+            thread
+                .process
+                .free_thread_stacks(thread.kernel_stack, thread.user_stack);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Kernel-wide lookup of processes and threads by id
+
+use crate::process::block::{Pid, ProcessRef, ThreadRef, WeakProcessRef, WeakThreadRef};
+use crate::spinlock::SpinLock;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use common::*;
+
+/// Only weak references are held, so registration doesn't keep anything alive. Entries are removed
+/// by the process/thread handle destructors.
+///
+/// Strong references must never be dropped while the lock is held, as the destructor of the last
+/// reference would try to take it again.
+struct Registry {
+    processes: BTreeMap<Pid, WeakProcessRef>,
+    threads: BTreeMap<Pid, WeakThreadRef>,
+}
+
+static mut REGISTRY: InitializedGlobal<SpinLock<Registry>> = InitializedGlobal::uninit();
+
+/// Must be called once only before any process/thread creation
+pub fn init() {
+    let registry = Registry {
+        processes: BTreeMap::new(),
+        threads: BTreeMap::new(),
+    };
+
+    unsafe {
+        REGISTRY.init(SpinLock::new(registry));
+    }
+}
+
+fn registry() -> &'static SpinLock<Registry> {
+    unsafe { REGISTRY.get() }
+}
+
+pub fn register_process(process: &ProcessRef) {
+    let pid = process.pid();
+    let prev = registry().lock().processes.insert(pid, process.downgrade());
+
+    // pids are never reused
+    assert!(prev.is_none(), "process {:?} registered twice", pid);
+}
+
+pub fn register_thread(thread: &ThreadRef) {
+    let tid = thread.tid();
+    let prev = registry().lock().threads.insert(tid, thread.downgrade());
+
+    assert!(prev.is_none(), "thread {:?} registered twice", tid);
+}
+
+/// Called from process destructor, so the weak reference is already dead
+pub fn unregister_process(pid: Pid) {
+    let removed = registry().lock().processes.remove(&pid);
+    debug_assert!(removed.is_some(), "process {:?} was not registered", pid);
+}
+
+/// Called from thread destructor, so the weak reference is already dead
+pub fn unregister_thread(tid: Pid) {
+    let removed = registry().lock().threads.remove(&tid);
+    debug_assert!(removed.is_some(), "thread {:?} was not registered", tid);
+}
+
+/// None if no process exists with this pid, or it is currently being destroyed
+pub fn process_by_pid(pid: Pid) -> Option<ProcessRef> {
+    let registry = registry().lock();
+    registry
+        .processes
+        .get(&pid)
+        .and_then(WeakProcessRef::upgrade)
+}
+
+/// None if no thread exists with this tid, or it is currently being destroyed
+pub fn thread_by_tid(tid: Pid) -> Option<ThreadRef> {
+    let registry = registry().lock();
+    registry.threads.get(&tid).and_then(WeakThreadRef::upgrade)
+}
+
+/// Snapshot of all live processes, ordered by pid
+pub fn processes() -> Vec<ProcessRef> {
+    let registry = registry().lock();
+    registry
+        .processes
+        .values()
+        .filter_map(WeakProcessRef::upgrade)
+        .collect()
+}
+
+/// Snapshot of all live threads across all processes, ordered by tid
+pub fn threads() -> Vec<ThreadRef> {
+    let registry = registry().lock();
+    registry
+        .threads
+        .values()
+        .filter_map(WeakThreadRef::upgrade)
+        .collect()
+}
+
+/// Logs every live process and its threads, with their CPU time
+pub fn log_all() {
+    let processes = processes();
+    info!("{} processes:", processes.len());
+
+    for process in processes {
+        let parent = process.parent().map(|p| p.pid());
+        match process.exit_code() {
+            Some(code) => info!(
+                "* process {:?} ({:?}, parent {:?}, {}) exited with code {}",
+                process.pid(),
+                process.privilege_level(),
+                parent,
+                process.cpu_time(),
+                code
+            ),
+            None => info!(
+                "* process {:?} ({:?}, parent {:?}, {})",
+                process.pid(),
+                process.privilege_level(),
+                parent,
+                process.cpu_time(),
+            ),
+        }
+
+        let inner = process.inner_locked();
+        for thread in inner.threads() {
+            match thread.name() {
+                Some(name) => info!(
+                    "  * thread {:?} '{}' ({:?}, {})",
+                    thread.tid(),
+                    name,
+                    thread.run_state(),
+                    thread.cpu_time()
+                ),
+                None => info!(
+                    "  * thread {:?} ({:?}, {})",
+                    thread.tid(),
+                    thread.run_state(),
+                    thread.cpu_time()
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Wall-clock time from the CMOS real time clock. It's only read once on boot, after which the
+//! time is kept by [clock::monotonic_ns] since then.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use common::{info, Bit};
+
+use crate::clock;
+use crate::io::Port;
+use crate::irq::InterruptsDisabled;
+
+const CMOS_ADDRESS: Port = Port::new(0x70);
+const CMOS_DATA: Port = Port::new(0x71);
+
+/// Also disables NMIs while selecting a register
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Unix time in nanoseconds at [clock::monotonic_ns] 0
+static BOOT_UNIX_NS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+struct DateTime {
+    year: u64,
+    month: u64,
+    day: u64,
+    hours: u64,
+    minutes: u64,
+    seconds: u64,
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        CMOS_ADDRESS.write_u8(NMI_DISABLE | reg);
+        CMOS_DATA.read_u8()
+    }
+}
+
+fn is_updating() -> bool {
+    read_register(REG_STATUS_A).bit(7)
+}
+
+fn read_raw() -> DateTime {
+    while is_updating() {}
+
+    DateTime {
+        year: read_register(REG_YEAR) as u64,
+        month: read_register(REG_MONTH) as u64,
+        day: read_register(REG_DAY) as u64,
+        hours: read_register(REG_HOURS) as u64,
+        minutes: read_register(REG_MINUTES) as u64,
+        seconds: read_register(REG_SECONDS) as u64,
+    }
+}
+
+fn from_bcd(val: u64) -> u64 {
+    (val >> 4) * 10 + (val & 0xf)
+}
+
+/// Reads the current date and time in UTC
+fn read_date_time() -> DateTime {
+    // read until two in a row match, so an update can't have happened halfway through
+    let mut time = read_raw();
+    loop {
+        let again = read_raw();
+        if again == time {
+            break;
+        }
+        time = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let is_binary = status_b.bit(2);
+    let is_24_hour = status_b.bit(1);
+
+    // in 12 hour mode the top bit of the hour is set for pm
+    let pm = !is_24_hour && time.hours.bit(7);
+    time.hours &= 0x7f;
+
+    if !is_binary {
+        time.year = from_bcd(time.year);
+        time.month = from_bcd(time.month);
+        time.day = from_bcd(time.day);
+        time.hours = from_bcd(time.hours);
+        time.minutes = from_bcd(time.minutes);
+        time.seconds = from_bcd(time.seconds);
+    }
+
+    if !is_24_hour {
+        // 12am is midnight
+        time.hours %= 12;
+        if pm {
+            time.hours += 12;
+        }
+    }
+
+    // TODO use the century register from the ACPI FADT
+    time.year += 2000;
+    time
+}
+
+/// Days since 1970-01-01 of the given date, which must be no earlier than that
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // count from March so the leap day is at the end of the year
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    // 1970-03-01 is day 719468 counting from 0000-03-01
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Reads the RTC, must be called after [clock::init]
+pub fn init() {
+    let (time, now_ns) = {
+        // keep the two readings close together
+        let _irq = InterruptsDisabled::acquire();
+        (read_date_time(), clock::monotonic_ns())
+    };
+
+    info!(
+        "RTC time is {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        time.year, time.month, time.day, time.hours, time.minutes, time.seconds
+    );
+
+    let days = days_since_epoch(time.year, time.month, time.day);
+    let secs = days * 86_400 + time.hours * 3600 + time.minutes * 60 + time.seconds;
+    let unix_ns = secs * 1_000_000_000;
+
+    BOOT_UNIX_NS.store(unix_ns.saturating_sub(now_ns), Ordering::Relaxed);
+}
+
+/// Unix time in nanoseconds when [clock::monotonic_ns] was 0
+pub fn boot_unix_ns() -> u64 {
+    BOOT_UNIX_NS.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since 1970-01-01 00:00 UTC
+pub fn unix_ns() -> u64 {
+    boot_unix_ns() + clock::monotonic_ns()
+}
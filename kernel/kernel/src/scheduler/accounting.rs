@@ -0,0 +1,79 @@
+//! CPU time accounting of threads and processes. Time is charged to the current thread at every
+//! transition between user and kernel mode and on every context switch, measured by
+//! [clock::monotonic_ns](crate::clock::monotonic_ns). This is only as precise as the clock, i.e.
+//! one tick until the TSC has been calibrated.
+
+use crate::clock;
+use crate::cpu::CpuState;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Time accumulated by a thread or process. Atomics so it can be updated from interrupt handlers
+#[derive(Default)]
+pub struct CpuTime {
+    user_ns: AtomicU64,
+    kernel_ns: AtomicU64,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum CpuMode {
+    User,
+    Kernel,
+}
+
+/// Timestamp of the last time charged
+// TODO per-cpu
+static LAST_CHARGE_NS: AtomicU64 = AtomicU64::new(0);
+
+impl CpuTime {
+    pub fn user_ns(&self) -> u64 {
+        self.user_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn kernel_ns(&self) -> u64 {
+        self.kernel_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn total_ns(&self) -> u64 {
+        self.user_ns() + self.kernel_ns()
+    }
+
+    fn add(&self, mode: CpuMode, ns: u64) {
+        let counter = match mode {
+            CpuMode::User => &self.user_ns,
+            CpuMode::Kernel => &self.kernel_ns,
+        };
+
+        counter.fetch_add(ns, Ordering::Relaxed);
+    }
+}
+
+impl Display for CpuTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "user {}us, kernel {}us",
+            self.user_ns() / 1000,
+            self.kernel_ns() / 1000
+        )
+    }
+}
+
+/// Charges the time since the last charge to the current thread and its process, as having been
+/// spent in the given mode. Called on the way into the kernel with [CpuMode::User], and on the way
+/// out or when switching away with [CpuMode::Kernel]. Safe to call from interrupt handlers.
+pub fn charge_current(mode: CpuMode) {
+    let elapsed = elapsed_since_last_charge();
+
+    // safety: only called after cpu state is initialised
+    if let Some(thread) = unsafe { CpuState::current_thread_opt() } {
+        thread.cpu_time().add(mode, elapsed);
+        thread.process().cpu_time().add(mode, elapsed);
+    }
+}
+
+fn elapsed_since_last_charge() -> u64 {
+    let now = clock::monotonic_ns();
+    let last = LAST_CHARGE_NS.swap(now, Ordering::Relaxed);
+    now.saturating_sub(last)
+}
@@ -0,0 +1,76 @@
+//! Switching between kernel stacks of threads
+
+use memory::VirtualAddress;
+
+/// Layout of the context saved on a kernel stack by [switch], from low to high addresses
+#[repr(C)]
+struct SavedContext {
+    rflags: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    rip: u64,
+}
+
+/// Reserved bit 1 is always set, interrupts are disabled
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/// Writes an initial context to the top of a new thread's kernel stack, so that the first switch
+/// to it "returns" into `entry` with interrupts disabled. Returns the stack pointer to resume.
+///
+/// # Safety
+/// Stack must be mapped, writeable and unused
+pub unsafe fn initial_context(stack_top: VirtualAddress, entry: extern "C" fn() -> !) -> u64 {
+    let ctx = SavedContext {
+        rflags: INITIAL_RFLAGS,
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: 0,
+        rbx: 0,
+        rbp: 0, // terminates backtraces
+        rip: entry as usize as u64,
+    };
+
+    // stack_top is 8 mod 16, so entry is "called" with the correct alignment once rip is popped
+    let ptr = (stack_top.address() as *mut SavedContext).offset(-1);
+    ptr.write(ctx);
+    ptr as u64
+}
+
+/// Saves callee-saved registers and rflags on the current stack and stores the stack pointer in
+/// `save_rsp`, then switches to `load_rsp` and restores the context saved there, either by a
+/// previous call to this or by [initial_context].
+///
+/// # Safety
+/// Interrupts must be disabled, and `load_rsp` must point to a saved context on a stack that is
+/// mapped in the current address space
+#[naked]
+pub unsafe extern "C" fn switch(save_rsp: *mut u64, load_rsp: u64) {
+    asm!(
+        // save context on current stack, must match SavedContext
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        // swap stacks
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        // restore context from new stack
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+    )
+}
@@ -0,0 +1,512 @@
+//! Priority round robin scheduling of threads on a single CPU. The highest priority ready thread
+//! always runs next, threads of equal priority take turns.
+//!
+//! Threads give up the CPU when they block or yield, or at a preemption point once their timeslice
+//! has run out or a higher priority thread has been woken. Preemption points are on every return
+//! to userspace from a syscall or interrupt. When nothing is ready the idle thread runs, halting
+//! until the next interrupt. Processes over their CPU time limit are killed at the next preemption
+//! point.
+//!
+//! Threads can also sleep until a deadline. The clock interrupt can't take the scheduler lock, so
+//! it only makes sure it fires in time, and expired sleepers are woken at the next preemption
+//! point or by the idle thread.
+
+mod accounting;
+mod context;
+mod priority;
+mod sleep;
+
+use crate::clock;
+use crate::cpu::CpuState;
+use crate::descriptor_tables::tss;
+use crate::irq::InterruptsDisabled;
+use crate::process::{
+    create_kernel_thread, exit_current_process, ThreadRef, ThreadRunState, EXIT_CODE_CPU_LIMIT,
+};
+use crate::spinlock::SpinLock;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use common::*;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub use accounting::{charge_current, CpuMode, CpuTime};
+pub use context::initial_context;
+pub use priority::{SchedulingClass, ThreadPriority, MAX_LEVEL};
+use sleep::Sleepers;
+
+/// How long a thread may run for before it should be preempted, if others are ready
+const TIMESLICE_NS: u64 = 25_000_000;
+
+struct Scheduler {
+    /// Threads in the `Ready` state, one queue per effective priority
+    run_queues: Vec<VecDeque<ThreadRef>>,
+
+    /// Bit n is set if run queue n is non-empty
+    ready_mask: u64,
+
+    /// Runs when nothing else is ready, never in a run queue
+    // TODO per-cpu
+    idle: ThreadRef,
+
+    sleepers: Sleepers,
+}
+
+static mut SCHEDULER: InitializedGlobal<SpinLock<Scheduler>> = InitializedGlobal::uninit();
+
+/// [clock::monotonic_ns] at which the current thread's timeslice ends, or u64::MAX if it has none.
+/// Atomic as it's read from the clock interrupt, which can't take the scheduler lock
+static SLICE_DEADLINE_NS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Earliest deadline of any sleeping thread, or u64::MAX if none. Atomic for the same reason as
+/// [SLICE_DEADLINE_NS]
+static NEXT_SLEEPER_NS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Set when the current thread should give up the CPU at the next preemption point
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Must be called after the kernel process is initialised
+pub fn init() {
+    let idle_priority = ThreadPriority::new(SchedulingClass::Idle, 0).unwrap(); // valid level
+    let idle = create_kernel_thread("idle", idle_priority, idle_loop)
+        .expect("failed to create idle thread");
+
+    let scheduler = Scheduler {
+        run_queues: (0..priority::PRIORITY_COUNT)
+            .map(|_| VecDeque::new())
+            .collect(),
+        ready_mask: 0,
+        idle,
+        sleepers: Sleepers::default(),
+    };
+
+    unsafe {
+        SCHEDULER.init(SpinLock::new(scheduler));
+    }
+}
+
+fn scheduler() -> &'static SpinLock<Scheduler> {
+    unsafe { SCHEDULER.get() }
+}
+
+impl Scheduler {
+    fn push(&mut self, thread: ThreadRef) {
+        let priority = thread.effective_priority();
+        self.run_queues[priority].push_back(thread);
+        self.ready_mask |= 1 << priority;
+    }
+
+    fn pop(&mut self) -> Option<ThreadRef> {
+        if self.ready_mask == 0 {
+            return None;
+        }
+
+        let priority = 63 - self.ready_mask.leading_zeros() as usize;
+        let queue = &mut self.run_queues[priority];
+        let thread = queue.pop_front();
+        if queue.is_empty() {
+            self.ready_mask &= !(1 << priority);
+        }
+
+        thread
+    }
+
+    /// Returns true if it was found and removed
+    fn remove(&mut self, thread: &ThreadRef, priority: usize) -> bool {
+        let queue = &mut self.run_queues[priority];
+        let idx = match queue.iter().position(|t| t.tid() == thread.tid()) {
+            Some(idx) => idx,
+            None => return false,
+        };
+
+        let removed = queue.remove(idx);
+        if queue.is_empty() {
+            self.ready_mask &= !(1 << priority);
+        }
+
+        // thread is still referenced by the caller so this can't be the last reference
+        drop(removed);
+        true
+    }
+
+    fn is_idle(&self, thread: &ThreadRef) -> bool {
+        thread.tid() == self.idle.tid()
+    }
+
+    /// Effective priority of the best ready thread, if any
+    fn highest_ready(&self) -> Option<usize> {
+        if self.ready_mask == 0 {
+            None
+        } else {
+            Some(63 - self.ready_mask.leading_zeros() as usize)
+        }
+    }
+}
+
+/// Makes a blocked thread runnable again. New threads are created blocked, so this is also used to
+/// start them. Returns false if the thread was not blocked, e.g. it was already woken by something
+/// else.
+///
+/// Normal threads are given a temporary priority boost, and if the woken thread now outranks the
+/// current one, the current one is preempted at the next preemption point.
+pub fn wake(thread: &ThreadRef) -> bool {
+    let _irq = InterruptsDisabled::acquire();
+
+    if thread.run_state() != ThreadRunState::Blocked {
+        return false;
+    }
+
+    if thread.priority().class() == SchedulingClass::Normal {
+        thread.set_boost(priority::WAKE_BOOST);
+    }
+
+    thread.set_run_state(ThreadRunState::Ready);
+    let priority = thread.effective_priority();
+    scheduler().lock().push(thread.clone());
+
+    // safety: cpu state is initialised before any threads are woken
+    if let Some(current) = unsafe { CpuState::current_thread_opt() } {
+        if priority > current.effective_priority() {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // the current thread may have had no competition until now, in which case its timeslice end
+    // has not been requested
+    let slice_deadline = SLICE_DEADLINE_NS.load(Ordering::Relaxed);
+    if slice_deadline != u64::MAX {
+        clock::request_wakeup_at(slice_deadline);
+    }
+
+    true
+}
+
+/// Gives up the CPU to the next runnable thread of equal or higher priority, if any
+pub fn yield_now() {
+    reschedule();
+}
+
+/// Yields if the current thread's timeslice has run out or a higher priority thread is waiting,
+/// and exits the current process if it has exceeded its CPU time limit. Expired sleepers are woken
+/// first so they can preempt the current thread. Must only be called from
+/// thread context where it's safe to switch, e.g. before returning to userspace from a syscall or
+/// an interrupt
+pub fn preemption_point() {
+    let over_cpu_limit = {
+        // safety: only called from thread context
+        let current = unsafe { CpuState::current_thread() };
+        let process = current.process();
+        let exceeded = process.limits().cpu_time_exceeded(process.cpu_time());
+        if exceeded {
+            debug!(
+                "process {:?} exceeded its CPU time limit ({})",
+                process.pid(),
+                process.cpu_time()
+            );
+        }
+        exceeded
+    };
+
+    if over_cpu_limit {
+        exit_current_process(EXIT_CODE_CPU_LIMIT);
+    }
+
+    wake_sleepers();
+
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        reschedule();
+    }
+}
+
+/// Called from the clock interrupt, returns the next deadline the clock should fire for, if any
+pub fn on_timer(now_ns: u64) -> Option<u64> {
+    let mut slice_deadline = SLICE_DEADLINE_NS.load(Ordering::Relaxed);
+    if now_ns >= slice_deadline {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+        slice_deadline = u64::MAX;
+    }
+
+    // expired sleepers are woken at the next preemption point, so only future ones need the clock
+    let mut sleeper_deadline = NEXT_SLEEPER_NS.load(Ordering::Relaxed);
+    if now_ns >= sleeper_deadline {
+        sleeper_deadline = u64::MAX;
+    }
+
+    match slice_deadline.min(sleeper_deadline) {
+        u64::MAX => None,
+        deadline => Some(deadline),
+    }
+}
+
+/// Blocks the current thread until the given [clock::monotonic_ns] deadline, returning immediately
+/// if it has already passed
+pub fn sleep_until(deadline_ns: u64) {
+    let _irq = InterruptsDisabled::acquire();
+
+    if clock::monotonic_ns() >= deadline_ns {
+        return;
+    }
+
+    {
+        // safety: only called from thread context
+        let current = unsafe { CpuState::current_thread() };
+        let mut scheduler = scheduler().lock();
+        scheduler.sleepers.push(deadline_ns, current);
+        NEXT_SLEEPER_NS.store(scheduler.sleepers.next_deadline(), Ordering::Relaxed);
+    }
+
+    clock::request_wakeup_at(deadline_ns);
+    block_current();
+}
+
+/// Wakes all sleeping threads whose deadline has passed. Must be called from thread context
+fn wake_sleepers() {
+    let now = clock::monotonic_ns();
+    if now < NEXT_SLEEPER_NS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let _irq = InterruptsDisabled::acquire();
+    loop {
+        // can't wake while holding the lock
+        let expired = {
+            let mut scheduler = scheduler().lock();
+            let expired = scheduler.sleepers.pop_expired(now);
+            NEXT_SLEEPER_NS.store(scheduler.sleepers.next_deadline(), Ordering::Relaxed);
+            expired
+        };
+
+        match expired {
+            Some(thread) => {
+                wake(&thread);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Changes the priority of any thread, moving it between run queues if it's ready. Any boost is
+/// reset
+pub fn set_priority(thread: &ThreadRef, priority: ThreadPriority) {
+    let _irq = InterruptsDisabled::acquire();
+
+    let mut scheduler = scheduler().lock();
+    let was_queued = thread.run_state() == ThreadRunState::Ready
+        && scheduler.remove(thread, thread.effective_priority());
+
+    thread.set_priority(priority);
+    thread.set_boost(0);
+
+    if was_queued {
+        scheduler.push(thread.clone());
+    }
+
+    // the current thread may no longer be the best choice
+    // safety: cpu state is initialised before any threads are created
+    let current = unsafe { CpuState::current_thread_opt() };
+    if let (Some(current), Some(best)) = (current, scheduler.highest_ready()) {
+        if best > current.effective_priority() {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Switches away from the current thread until it is woken with [wake]. It must be reachable from
+/// somewhere that will wake it, e.g. a wait queue.
+///
+/// Interrupts must be disabled by the caller between making the thread reachable and calling
+/// this, so that it can't be woken in between.
+pub fn block_current() {
+    // safety: only called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    current.set_run_state(ThreadRunState::Blocked);
+    drop(current);
+
+    reschedule();
+}
+
+/// Switches to the next thread in the run queue. If the current thread is still running it is put
+/// to the back of the queue, otherwise it must have been blocked.
+fn reschedule() {
+    let _irq = InterruptsDisabled::acquire();
+
+    // safety: only called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let still_running = current.run_state() == ThreadRunState::Running;
+
+    if still_running {
+        // gave up the CPU without blocking, so looks CPU-bound
+        let boost = current.boost();
+        if boost > 0 {
+            current.set_boost(boost - 1);
+        }
+
+        // don't switch to a lower priority thread
+        let current_priority = current.effective_priority();
+        let outranked = scheduler()
+            .lock()
+            .highest_ready()
+            .map(|best| best >= current_priority)
+            .unwrap_or(false);
+
+        if !outranked {
+            // nothing better to run, carry on with a fresh timeslice
+            start_timeslice(&current);
+            return;
+        }
+    }
+
+    let next = match next_thread(still_running) {
+        Some(next) => next,
+        None => {
+            // nothing else to run, carry on
+            return;
+        }
+    };
+
+    if still_running {
+        current.set_run_state(ThreadRunState::Ready);
+
+        let mut scheduler = scheduler().lock();
+        if !scheduler.is_idle(&current) {
+            scheduler.push(current.clone());
+        }
+    }
+
+    // our reference to current keeps it alive until we're switched back to
+    unsafe { switch_to(current.saved_kernel_rsp_ptr(), next) }
+}
+
+/// Switches away from the current thread for the last time. It should already have been
+/// removed from its process, so the reference held by the cpu state is likely the last one
+pub fn exit_current() -> ! {
+    /// Context of exited threads is saved here and never resumed, the thread handle may have
+    /// been freed by the time the switch happens
+    static mut EXITED_RSP: u64 = 0;
+
+    let _irq = InterruptsDisabled::acquire();
+
+    {
+        // safety: only called from thread context
+        let current = unsafe { CpuState::current_thread() };
+        current.set_run_state(ThreadRunState::Exited);
+        trace!("thread {:?} exited", current.tid());
+    }
+
+    let next = next_thread(false).unwrap(); // always Some when current can't continue
+    unsafe {
+        switch_to(&mut EXITED_RSP, next);
+    }
+
+    unreachable!("exited thread resumed")
+}
+
+/// Pops the next thread to run. If none are ready and `current_can_continue` is false, returns the
+/// idle thread
+fn next_thread(current_can_continue: bool) -> Option<ThreadRef> {
+    let mut scheduler = scheduler().lock();
+    match scheduler.pop() {
+        some @ Some(_) => some,
+        None if current_can_continue => None,
+        None => Some(scheduler.idle.clone()),
+    }
+}
+
+/// Body of the idle thread, which runs with interrupts enabled
+fn idle_loop() {
+    loop {
+        {
+            let _irq = InterruptsDisabled::acquire();
+
+            // the clock may have fired for a sleeper. Checked with interrupts disabled, as the
+            // clock isn't rearmed for an expired sleeper so one firing before the hlt would be
+            // missed until some other interrupt
+            wake_sleepers();
+
+            if scheduler().lock().highest_ready().is_none() {
+                // wait for an interrupt, sti only takes effect after the next instruction so one
+                // can't be missed in between
+                unsafe { asm!("sti", "hlt") };
+            }
+        }
+
+        // switches to anything that was woken
+        yield_now();
+    }
+}
+
+/// Sets the timeslice deadline for a thread that has just started running, and makes sure the
+/// clock fires for it if other threads are waiting
+fn start_timeslice(thread: &ThreadRef) {
+    let scheduler = scheduler().lock();
+    if scheduler.is_idle(thread) {
+        SLICE_DEADLINE_NS.store(u64::MAX, Ordering::Relaxed);
+        return;
+    }
+
+    let deadline = clock::monotonic_ns() + TIMESLICE_NS;
+    SLICE_DEADLINE_NS.store(deadline, Ordering::Relaxed);
+
+    // otherwise wake requests it if something becomes ready
+    if scheduler.highest_ready().is_some() {
+        clock::request_wakeup_at(deadline);
+    }
+}
+
+/// Switches from the boot stack to the first runnable thread, never to return
+pub fn start() -> ! {
+    /// Boot context is saved here and never resumed
+    static mut BOOT_RSP: u64 = 0;
+
+    let _irq = InterruptsDisabled::acquire();
+
+    let first = next_thread(false).unwrap(); // always Some when current can't continue
+
+    debug!("starting scheduler with thread {:?}", first.tid());
+
+    unsafe {
+        switch_to(&mut BOOT_RSP, first);
+    }
+
+    unreachable!("boot context resumed")
+}
+
+/// Saves the current context to `save_rsp` and resumes `next`. Returns when the saved context is
+/// switched back to.
+///
+/// # Safety
+/// Interrupts must be disabled, and `save_rsp` must remain valid until the switch happens
+unsafe fn switch_to(save_rsp: *mut u64, next: ThreadRef) {
+    // time since entering the kernel goes to the previous thread
+    charge_current(CpuMode::Kernel);
+
+    // kernel threads don't touch the user segment bases, so they stay loaded until the next user
+    // thread is switched to
+    if let Some(prev) = CpuState::current_thread_opt() {
+        if prev.is_user() {
+            prev.save_user_segment_bases();
+        }
+    }
+
+    next.set_run_state(ThreadRunState::Running);
+    start_timeslice(&next);
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+
+    let load_rsp = next.saved_kernel_rsp();
+
+    // interrupts from userspace land on the thread's own kernel stack, so it can be switched away
+    // from inside the handler without another thread trampling its state
+    tss().set_privilege_stack_quietly(0, next.kernel_stack());
+
+    // kernel stacks are mapped in every address space, so we can continue on this one after
+    // switching
+    next.address_space().load_if_not_current();
+
+    if next.is_user() {
+        next.load_user_segment_bases();
+    }
+
+    let prev = CpuState::update_current_thread(next);
+    drop(prev);
+
+    context::switch(save_rsp, load_rsp);
+}
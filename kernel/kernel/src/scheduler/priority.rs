@@ -0,0 +1,94 @@
+//! Thread priorities, grouped into scheduling classes. Higher classes always run before lower
+//! ones, and within a class higher levels run first
+
+use core::convert::TryFrom;
+
+/// Levels within each class are `0..=MAX_LEVEL`
+pub const MAX_LEVEL: u8 = 15;
+
+const LEVELS_PER_CLASS: usize = MAX_LEVEL as usize + 1;
+
+/// Number of distinct effective priorities, one run queue each
+pub const PRIORITY_COUNT: usize = LEVELS_PER_CLASS * 3;
+
+/// Added to the level of normal threads when woken from blocking, e.g. on I/O, so interactive
+/// threads get in ahead of CPU-bound ones. Decays by one each time the thread gives up the CPU
+/// without blocking
+pub const WAKE_BOOST: u8 = 4;
+
+/// Ordered from lowest to highest
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SchedulingClass {
+    /// Only runs when nothing else is runnable
+    Idle = 0,
+
+    /// Default for all threads, with dynamic priority boost
+    Normal = 1,
+
+    /// Drivers and other latency-sensitive kernel threads, never boosted
+    Realtime = 2,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ThreadPriority {
+    class: SchedulingClass,
+    level: u8,
+}
+
+impl ThreadPriority {
+    /// None if level is out of range
+    pub fn new(class: SchedulingClass, level: u8) -> Option<Self> {
+        if level <= MAX_LEVEL {
+            Some(Self { class, level })
+        } else {
+            None
+        }
+    }
+
+    pub fn class(self) -> SchedulingClass {
+        self.class
+    }
+
+    pub fn level(self) -> u8 {
+        self.level
+    }
+
+    /// Index of the run queue to use, higher runs first. Boost only applies to the normal class
+    /// and can't lift a thread out of it
+    pub fn effective(self, boost: u8) -> usize {
+        let level = match self.class {
+            SchedulingClass::Normal => self.level.saturating_add(boost).min(MAX_LEVEL),
+            _ => self.level,
+        };
+
+        self.class as usize * LEVELS_PER_CLASS + level as usize
+    }
+
+    /// Packed as `class << 8 | level` for syscalls
+    pub fn to_u32(self) -> u32 {
+        (self.class as u32) << 8 | self.level as u32
+    }
+}
+
+impl Default for ThreadPriority {
+    fn default() -> Self {
+        Self {
+            class: SchedulingClass::Normal,
+            level: MAX_LEVEL / 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for SchedulingClass {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Idle),
+            1 => Ok(Self::Normal),
+            2 => Ok(Self::Realtime),
+            _ => Err(value),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::process::ThreadRef;
+use alloc::collections::VecDeque;
+
+/// Threads blocked until a [clock::monotonic_ns](crate::clock::monotonic_ns) deadline, in order of
+/// deadline
+#[derive(Default)]
+pub struct Sleepers(VecDeque<(u64, ThreadRef)>);
+
+impl Sleepers {
+    /// Threads with the same deadline are woken in the order they went to sleep
+    pub fn push(&mut self, deadline_ns: u64, thread: ThreadRef) {
+        let idx = self
+            .0
+            .iter()
+            .position(|(deadline, _)| *deadline > deadline_ns)
+            .unwrap_or(self.0.len());
+        self.0.insert(idx, (deadline_ns, thread));
+    }
+
+    /// Removes the first thread whose deadline has passed
+    pub fn pop_expired(&mut self, now_ns: u64) -> Option<ThreadRef> {
+        match self.0.front() {
+            Some((deadline, _)) if *deadline <= now_ns => self.0.pop_front().map(|(_, t)| t),
+            _ => None,
+        }
+    }
+
+    /// Earliest deadline, or u64::MAX if none are sleeping
+    pub fn next_deadline(&self) -> u64 {
+        self.0
+            .front()
+            .map(|(deadline, _)| *deadline)
+            .unwrap_or(u64::MAX)
+    }
+}
@@ -0,0 +1,107 @@
+//! The [KernelSharedData] page, mapped read-only into every user process at
+//! [KERNEL_SHARED_DATA_ADDRESS] so the time and CPU features can be read without a syscall.
+//!
+//! A single physical frame is shared by all processes, and written by the kernel through the
+//! physical identity mapping: the boot time, TSC calibration and CPU features once on boot, and the
+//! tick fields on every clock interrupt.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::Ordering;
+
+use common::InitializedGlobalChecked;
+use enumflags2::BitFlags;
+use memory::{MapFlags, MapTarget, MemoryError, PhysicalFrame, VirtualAddress, FRAME_SIZE};
+use syscall::{CpuFeatureRegister, KernelSharedData, KERNEL_SHARED_DATA_ADDRESS};
+
+use crate::memory::{frame_allocator, AddressSpace, FrameAllocator};
+use crate::{clock, rtc};
+
+struct SharedPage {
+    frame: PhysicalFrame,
+
+    /// Kernel view of the frame through the physical identity mapping
+    data: &'static KernelSharedData,
+}
+
+static mut SHARED_PAGE: InitializedGlobalChecked<SharedPage> = InitializedGlobalChecked::uninit();
+
+/// Must be called once after memory is initialised and before the clock interrupt is enabled
+pub fn init() -> Result<(), MemoryError> {
+    let frame = frame_allocator().allocate(BitFlags::empty())?;
+    frame.zero();
+
+    // safety: frame is zeroed, which is valid for a struct of atomics, and never freed
+    let data = unsafe {
+        let ptr: *mut KernelSharedData = VirtualAddress::from_physical(frame.address()).as_ptr();
+        ptr.write(KernelSharedData::new());
+        &*ptr
+    };
+
+    data.boot_unix_ns
+        .store(rtc::boot_unix_ns(), Ordering::Relaxed);
+
+    let (boot_tsc, tsc_hz) = clock::tsc_calibration();
+    data.boot_tsc.store(boot_tsc, Ordering::Relaxed);
+    data.tsc_hz.store(tsc_hz, Ordering::Relaxed);
+
+    let features = cpu_features();
+    for (register, value) in features.iter() {
+        data.cpu_features[*register as usize].store(*value, Ordering::Relaxed);
+    }
+
+    // safety: called once during init, before anything else can access it
+    unsafe {
+        SHARED_PAGE.init(SharedPage { frame, data });
+    }
+
+    Ok(())
+}
+
+/// Maps the shared page read-only into the given user address space
+pub fn map_into(address_space: &mut AddressSpace) -> Result<(), MemoryError> {
+    // safety: only written once during init
+    let frame = unsafe { SHARED_PAGE.get().frame };
+    let addr = VirtualAddress::with_literal(KERNEL_SHARED_DATA_ADDRESS);
+
+    // TODO support MapTarget::Specific in map_range instead of rewriting the demand mapping
+    address_space.map_range(addr, FRAME_SIZE, MapTarget::Any, MapFlags::User)?;
+
+    // TODO don't free this frame when address spaces are freed
+    let (_level, mapping) = address_space.get_absent_mapping(addr)?;
+    mapping
+        .as_builder()
+        .address(frame.address())
+        .present()
+        .apply();
+
+    Ok(())
+}
+
+/// Called on every clock interrupt with the current [monotonic_ns](clock::monotonic_ns)
+pub fn on_tick(now_ns: u64) {
+    // safety: only called from the clock interrupt, so can't race with itself
+    unsafe {
+        if SHARED_PAGE.is_initialized() {
+            SHARED_PAGE.get().data.record_tick(now_ns);
+        }
+    }
+}
+
+fn cpu_features() -> [(CpuFeatureRegister, u32); 4] {
+    // safety: cpuid is always available in long mode
+    unsafe {
+        let leaf1 = __cpuid(1);
+        let leaf7 = if __cpuid(0).eax >= 7 {
+            __cpuid_count(7, 0)
+        } else {
+            core::mem::zeroed()
+        };
+
+        [
+            (CpuFeatureRegister::Leaf1Ecx, leaf1.ecx),
+            (CpuFeatureRegister::Leaf1Edx, leaf1.edx),
+            (CpuFeatureRegister::Leaf7Ebx, leaf7.ebx),
+            (CpuFeatureRegister::Leaf7Ecx, leaf7.ecx),
+        ]
+    }
+}
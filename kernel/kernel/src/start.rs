@@ -2,16 +2,19 @@ use common::*;
 
 use crate::cpu::CpuState;
 use crate::descriptor_tables::{SEL_KERNEL_CODE, SEL_USER_CODE};
-use crate::io::{Efer, GsBase, KernelGsBase, LStar, Msr, Star};
+use crate::io::{Efer, GsBase, KernelGsBase, LStar, Msr, SfMask, Star};
 use crate::irq::{disable_interrupts, enable_interrupts};
 use crate::logging::LogMode;
-use crate::memory::{KernelInterruptStacks, Stacks};
+use crate::memory::{AddressSpace, KernelInterruptStacks, Stacks};
 use crate::multiboot;
 use crate::multiboot::Multiboot;
 use crate::vga::{self, Color};
-use crate::{clock, descriptor_tables, logging};
+use crate::{clock, descriptor_tables, logging, rtc};
 use memory::VirtualAddress;
 
+/// IF, DF and TF
+const SYSCALL_RFLAGS_MASK: u64 = 0x700;
+
 // TODO guard page to detect and handle stack overflow
 pub fn start(multiboot: &'static multiboot::multiboot_info) -> ! {
     vga::init(Color::LightGreen, Color::Black);
@@ -19,8 +22,12 @@ pub fn start(multiboot: &'static multiboot::multiboot_info) -> ! {
 
     descriptor_tables::init();
     clock::init();
+    rtc::init();
 
     let multiboot = Multiboot::new(multiboot);
+    if multiboot.has_arg("strace") {
+        crate::syscall::set_global_tracing(true);
+    }
 
     // init memory and get ourselves a heap
     if let Err(err) = crate::memory::init(multiboot) {
@@ -28,6 +35,11 @@ pub fn start(multiboot: &'static multiboot::multiboot_info) -> ! {
         hang();
     }
 
+    if let Err(err) = crate::shared_data::init() {
+        error!("failed to allocate kernel shared data: {}", err);
+        hang();
+    }
+
     // now we have a heap we can start using boxed error types
 
     // finally enable interrupts now that the higher half mappings are in place, so the isrs are
@@ -37,31 +49,36 @@ pub fn start(multiboot: &'static multiboot::multiboot_info) -> ! {
     // TODO 1 stack per core only, this needs to be shared
     let mut interrupt_stacks = Stacks::<KernelInterruptStacks>::new();
     let (interrupt_stack, _) = interrupt_stacks
-        .new_stack()
+        .new_stack(&mut AddressSpace::current())
         .expect("failed to map kernel interrupt stack");
 
     // prepare for syscalls, processes and userspace
     enable_syscalls();
-    crate::process::init_kernel_process();
+    crate::process::init();
+    crate::scheduler::init();
+    crate::sync::futex::init();
+    crate::process::spawn_reaper();
 
     // init per-cpu state
     let _cpu = init_cpu_state(interrupt_stack);
 
-    // use this stack for interrupts on this cpu
-    // TODO is tss shared or unique to cpu?
+    // use this stack for interrupts from userspace until the scheduler starts, after which it's
+    // replaced by the current thread's kernel stack on every switch
     crate::descriptor_tables::tss().set_privilege_stack(0, interrupt_stack);
 
     // begin testing
-    let process = crate::process::experiment_new_process().expect("failed");
+    let (_process, thread) = crate::process::spawn_process(
+        &crate::process::kernel_process(),
+        "syscall.exe",
+        "syscall.exe",
+        &[("DOMEOS", "1")],
+    )
+    .expect("failed");
     debug!("process created");
+    crate::process::log_all();
 
-    let thread = {
-        let inner = process.inner_locked();
-        let thread = inner.threads().next().expect("no main thread");
-        thread.clone()
-    };
-
-    unsafe { thread.switch_to() }
+    crate::scheduler::wake(&thread);
+    crate::scheduler::start()
 }
 
 fn hang() -> ! {
@@ -84,6 +101,11 @@ fn enable_syscalls() {
         let lstar = LStar::with_value(crate::syscall::syscall_entry as *const () as u64);
         lstar.store();
 
+        // userspace runs with interrupts enabled, but they must stay disabled until the syscall
+        // entrypoint has swapped gs and switched stacks. DF and TF are cleared too
+        let sfmask = SfMask::with_value(SYSCALL_RFLAGS_MASK);
+        sfmask.store();
+
         // register syscall segments
         let mut star = Star::load();
         star.set_sysret(SEL_USER_CODE as u16); // cs = this+16, SS.Sel = this+8
@@ -97,7 +119,8 @@ fn init_cpu_state(interrupt_stack_top: VirtualAddress) -> &'static mut CpuState
     let state = crate::cpu::CpuState::new(interrupt_stack_top);
 
     unsafe {
-        KernelGsBase::with_value(state as u64).store();
+        // kernel gs is swapped out for the current thread's user gs base on return to userspace
+        KernelGsBase::with_value(0).store();
         GsBase::with_value(state as u64).store();
 
         &mut *state
@@ -0,0 +1,60 @@
+use crate::irq::InterruptsDisabled;
+use crate::sync::{Mutex, MutexGuard, WaitQueue};
+
+/// Condition variable used with a [Mutex]
+pub struct CondVar {
+    waiters: WaitQueue,
+}
+
+impl CondVar {
+    pub fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Atomically unlocks the mutex and blocks until notified, then locks it again before
+    /// returning. Spurious wakeups are possible so the condition should be checked in a loop, or
+    /// use [wait_while](Self::wait_while)
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = {
+            // nothing can notify between unlocking and blocking
+            let _irq = InterruptsDisabled::acquire();
+
+            let mutex = Mutex::unlock_guard(guard);
+            self.waiters.wait();
+            mutex
+        };
+
+        mutex.lock()
+    }
+
+    /// Blocks while `condition` is true
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    /// Wakes one waiting thread, returning false if there were none
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    /// Wakes all waiting threads, returning how many were woken
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,42 @@
+use crate::sync::WaitQueue;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// One-shot event. Once set it stays set, and all current and future waiters return immediately
+pub struct Event {
+    set: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Blocks until the event is set
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.is_set());
+    }
+
+    /// Sets the event and wakes all waiters. Returns false if it was already set
+    pub fn set(&self) -> bool {
+        let was_set = self.set.swap(true, Ordering::AcqRel);
+        if !was_set {
+            self.waiters.wake_all();
+        }
+
+        !was_set
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
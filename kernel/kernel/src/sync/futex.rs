@@ -0,0 +1,77 @@
+//! Wait queues keyed by physical address, for userspace to build sleeping locks on top of atomic
+//! words in its own memory. Keying by physical rather than virtual address means processes sharing
+//! memory can wait on each other, wherever the word is mapped.
+//!
+//! Queues are created by the first waiter and removed once they have no waiters left.
+
+use crate::irq::InterruptsDisabled;
+use crate::spinlock::SpinLock;
+use crate::sync::WaitQueue;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use common::InitializedGlobal;
+use memory::PhysicalAddress;
+
+static mut FUTEXES: InitializedGlobal<SpinLock<BTreeMap<u64, Arc<WaitQueue>>>> =
+    InitializedGlobal::uninit();
+
+/// Must be called once only before any futex is waited on
+pub fn init() {
+    unsafe {
+        FUTEXES.init(SpinLock::new(BTreeMap::new()));
+    }
+}
+
+fn futexes() -> &'static SpinLock<BTreeMap<u64, Arc<WaitQueue>>> {
+    unsafe { FUTEXES.get() }
+}
+
+/// Blocks the current thread on the futex at `addr` if `condition` returns true, returning false
+/// immediately otherwise. Checking the condition and starting to wait is atomic with respect to
+/// [wake], so a wake after the condition changes can't be missed.
+///
+/// Returns true once woken, which may be spuriously.
+pub fn wait(addr: PhysicalAddress, condition: impl FnOnce() -> bool) -> bool {
+    let _irq = InterruptsDisabled::acquire();
+
+    if !condition() {
+        return false;
+    }
+
+    let queue = futexes().lock().entry(addr.0).or_default().clone();
+    queue.wait();
+
+    remove_if_unused(addr, &queue);
+    true
+}
+
+/// Wakes up to `count` threads waiting on the futex at `addr`, longest waiting first, and returns
+/// how many were woken
+pub fn wake(addr: PhysicalAddress, count: usize) -> usize {
+    let _irq = InterruptsDisabled::acquire();
+
+    let queue = match futexes().lock().get(&addr.0) {
+        Some(queue) => queue.clone(),
+        None => return 0,
+    };
+
+    let mut woken = 0;
+    while woken < count && queue.wake_one() {
+        woken += 1;
+    }
+
+    remove_if_unused(addr, &queue);
+    woken
+}
+
+/// Removes the queue for `addr` if it's still `queue` and has no waiters
+fn remove_if_unused(addr: PhysicalAddress, queue: &Arc<WaitQueue>) {
+    let mut futexes = futexes().lock();
+    let unused = futexes.get(&addr.0).map_or(false, |existing| {
+        Arc::ptr_eq(existing, queue) && queue.is_empty()
+    });
+
+    if unused {
+        futexes.remove(&addr.0);
+    }
+}
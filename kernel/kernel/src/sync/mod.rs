@@ -0,0 +1,15 @@
+//! Sleeping synchronization primitives, which block the current thread through the scheduler
+//! instead of spinning
+
+mod condvar;
+mod event;
+pub mod futex;
+mod mutex;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::CondVar;
+pub use event::Event;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,82 @@
+use crate::cpu::CpuState;
+use crate::irq::InterruptsDisabled;
+use crate::process::ThreadRef;
+use crate::scheduler;
+use crate::spinlock::SpinLock;
+use alloc::collections::VecDeque;
+
+/// Threads blocked until woken by another thread. The building block for the other sleeping
+/// primitives.
+///
+/// Must only be used from thread context, not interrupt handlers.
+// TODO SMP: waiting relies on interrupts being disabled to avoid missed wakeups
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<ThreadRef>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current thread until woken
+    pub fn wait(&self) {
+        let _irq = InterruptsDisabled::acquire();
+
+        // safety: only called from thread context
+        let current = unsafe { CpuState::current_thread() };
+        self.waiters.lock().push_back(current);
+
+        scheduler::block_current();
+    }
+
+    /// Blocks the current thread until `condition` returns true. Checking the condition and
+    /// starting to wait is atomic with respect to wakers
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let _irq = InterruptsDisabled::acquire();
+
+        while !condition() {
+            self.wait();
+        }
+    }
+
+    /// Wakes the longest waiting thread. Returns false if there were none
+    pub fn wake_one(&self) -> bool {
+        let _irq = InterruptsDisabled::acquire();
+
+        loop {
+            let thread = match self.waiters.lock().pop_front() {
+                Some(thread) => thread,
+                None => return false,
+            };
+
+            // skip any that have already been woken through other means
+            if scheduler::wake(&thread) {
+                return true;
+            }
+        }
+    }
+
+    /// Wakes all waiting threads, returning how many were woken
+    pub fn wake_all(&self) -> usize {
+        let _irq = InterruptsDisabled::acquire();
+
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        waiters
+            .iter()
+            .filter(|thread| scheduler::wake(thread))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,471 @@
+//! Syscall entry and return, and the machinery for the table of handlers defined with [syscalls].
+//!
+//! There is a single entrypoint for all syscalls. It switches to the kernel stack and pushes the
+//! six argument registers as [SyscallArgs], then calls the handler wrapper generated for the
+//! syscall number, which converts the raw arguments to the types the handler declares. Its result
+//! is returned in rax, with an optional second value in rdx.
+
+use crate::cpu::CpuState;
+use crate::descriptor_tables::{SEL_USER_CODE, SEL_USER_DATA};
+use crate::irq::InterruptContext;
+use crate::memory::{UserData, UserPtr};
+use crate::process::{self, Handle, Pid, Resource, UserSegment};
+use crate::scheduler::{self, CpuMode, SchedulingClass};
+use core::convert::TryFrom;
+use syscall::{FilterAction, HandleRights, SyscallError, SyscallResult};
+
+/// Argument registers in order: rdi, rsi, rbx, rdx, r8, r9
+pub type SyscallArgs = [u64; 6];
+
+/// Generated for each syscall by [syscalls]
+pub type SyscallHandler = extern "C" fn(&SyscallArgs) -> SyscallReturn;
+
+/// Values returned to userspace from a syscall, in rax and rdx. The C ABI returns a struct of two
+/// integers in the same registers
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SyscallReturn {
+    pub result: SyscallResult,
+
+    /// Second value, 0 if unused or on error
+    pub extra: u64,
+}
+
+/// Conversion from a raw argument register to the type declared by a syscall handler. A failed
+/// conversion fails the syscall with [SyscallError::InvalidArguments]
+pub trait SyscallArg: Sized {
+    fn from_arg(arg: u64) -> Option<Self>;
+}
+
+/// Conversion from the return value of a syscall handler to the values in rax and rdx
+pub trait IntoSyscallReturn {
+    fn into_syscall_return(self) -> SyscallReturn;
+}
+
+/// Defines the syscall table from a list of `Number => handler(arg: Type, ...);` entries, one for
+/// each [SyscallNumber](syscall::SyscallNumber) in order. Handlers are plain Rust functions taking
+/// up to 6 arguments that implement [SyscallArg], with user pointers as [UserPtr], and return a
+/// `Result<_, SyscallError>` that implements [IntoSyscallReturn]. Each syscall is checked against
+/// the calling process' syscall filter before its arguments are converted.
+macro_rules! syscalls {
+    ($($num:ident => $handler:ident($($arg:ident: $ty:ty),* $(,)?);)*) => {
+        const COUNT: usize = ::syscall::SYSCALL_COUNT;
+
+        // fails to compile if the numbers don't match the position in the table. HANDLERS fails to
+        // compile if any are missing
+        const _: () = [()][!$crate::syscall::dispatch::numbered_in_order(
+            &[$(::syscall::SyscallNumber::$num as usize),*],
+        ) as usize];
+
+        static HANDLERS: [$crate::syscall::dispatch::SyscallHandler; COUNT] = [$({
+            extern "C" fn handle(
+                args: &$crate::syscall::dispatch::SyscallArgs,
+            ) -> $crate::syscall::dispatch::SyscallReturn {
+                #[allow(unused_imports)]
+                use $crate::syscall::dispatch::{IntoSyscallReturn, SyscallArg};
+                use $crate::syscall::trace::SyscallTrace;
+
+                $crate::syscall::dispatch::enter_syscall();
+                let trace = SyscallTrace::begin(::syscall::SyscallNumber::$num);
+
+                #[allow(unused_mut, unused_variables)]
+                let mut regs = args.iter().copied();
+                let filtered = $crate::syscall::filter::check(::syscall::SyscallNumber::$num);
+                let result = if let Err(err) = filtered {
+                    if let Some(trace) = &trace {
+                        trace.log_denied(args);
+                    }
+
+                    Err(err)
+                } else {
+                    match ($(<$ty as SyscallArg>::from_arg(regs.next().unwrap()),)*) {
+                        ($(Some($arg),)*) => {
+                            if let Some(trace) = &trace {
+                                trace.log_args(&[$((stringify!($arg), &$arg as &dyn core::fmt::Debug)),*]);
+                            }
+
+                            $handler($($arg),*)
+                        }
+                        #[allow(unreachable_patterns)]
+                        _ => {
+                            if let Some(trace) = &trace {
+                                trace.log_raw_args(args);
+                            }
+
+                            Err(::syscall::SyscallError::InvalidArguments)
+                        }
+                    }
+                };
+
+                let ret = result.into_syscall_return();
+                if let Some(trace) = trace {
+                    trace.end(ret);
+                }
+
+                $crate::syscall::dispatch::leave_syscall();
+                ret
+            }
+
+            handle
+        }),*];
+    };
+}
+
+/// See [syscalls]
+pub const fn numbered_in_order(numbers: &[usize]) -> bool {
+    let mut i = 0;
+    while i < numbers.len() {
+        if numbers[i] != i {
+            return false;
+        }
+        i += 1;
+    }
+
+    true
+}
+
+#[naked]
+pub unsafe extern "C" fn syscall_entry() -> ! {
+    const ERR_INVALID: u64 = SyscallResult::error(SyscallError::InvalidSyscall).to_u64();
+
+    asm!(
+        // rax=syscall number
+        // rcx=rip to return to, must be restored before sysret
+        // r11=rflags
+        // rdi, rsi, rbx, rdx, r8, r9=arguments
+
+        // make kernel structures available
+        "swapgs",
+
+        // check syscall number is valid. the comparison is unsigned, so negative numbers are
+        // rejected too
+        "cmp rax, {syscall_count}",
+        "jae 1f",
+
+        // switch to kernel stack
+        "mov r10, rsp",
+        "mov rsp, gs:{gs_stack_offset}",
+
+        // preserve values for the return
+        "push rcx", // user rip
+        "push r10", // user rsp
+        "push r11", // user rflags
+
+        // SyscallArgs
+        "push r9",
+        "push r8",
+        "push rdx",
+        "push rbx",
+        "push rsi",
+        "push rdi",
+
+        "mov rdi, rsp",
+        "call qword ptr [{handlers} + 8 * rax]",
+        "add rsp, {args_size}",
+        "jmp {common_return}",
+
+        // bad syscall. we can sysret immediately without any other reg restoring required because
+        // no other registers have been clobbered or functions called
+        "1: mov rax, {err_invalid}",
+        "xor edx, edx",
+        "swapgs",
+        "sysretq",
+
+        syscall_count = const super::COUNT,
+        gs_stack_offset = const CpuState::THREAD_KERNEL_STACK_OFFSET,
+        handlers = sym super::HANDLERS,
+        args_size = const core::mem::size_of::<SyscallArgs>(),
+        common_return = sym common_return,
+        err_invalid = const ERR_INVALID,
+        options(noreturn)
+    )
+}
+
+/// Returns to userspace after a syscall handler has run
+///
+/// Assumptions:
+/// * swapgs has been run once
+/// * we're on kernel stack of current thread
+/// * stack is (top-->bottom) [ user rflags, user rsp, user rip ]
+/// * rax and rdx hold the [SyscallReturn]
+#[naked]
+unsafe extern "C" fn common_return() -> ! {
+    asm!(
+        // take the slow path back if needed, e.g. to enter an APC. the stack stays aligned for
+        // the call
+        "push rax",
+        "push rdx",
+        "call {needs_full_return}",
+        "test al, al",
+        "pop rdx",
+        "pop rax",
+        "jnz {full_return}",
+
+        "pop r11", // user rflags
+        "pop r10", // user rsp
+        "pop rcx", // user rip
+        // restore user stack
+        "mov rsp, r10",
+        // back we go
+        "swapgs",
+        "sysretq",
+
+        needs_full_return = sym needs_full_return,
+        full_return = sym full_return,
+        options(noreturn)
+    )
+}
+
+/// Returns to userspace with iretq rather than sysret, after saving the full user context as an
+/// [InterruptContext] on the kernel stack and passing it to [process::finish_user_return] to
+/// modify. Slower than [common_return], but every register can be set.
+///
+/// Assumptions are as for [common_return]. Registers clobbered by the syscall ABI hold garbage in
+/// the context, other than rax, rdx and those preserved by the C ABI.
+#[naked]
+unsafe extern "C" fn full_return() -> ! {
+    asm!(
+        "pop r11", // user rflags
+        "pop r10", // user rsp
+        "pop rcx", // user rip
+
+        // the cpu aligns the stack before pushing an interrupt frame, so must we
+        "sub rsp, 8",
+
+        // interrupt frame as pushed by the cpu
+        "push {ss_user}",
+        "push r10",
+        "push r11",
+        "push {cs_user}",
+        "push rcx",
+
+        // error code and int no as pushed by the isr stubs
+        "push 0",
+        "push 0",
+
+        // GPRs in the same order as the isr stubs
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push r11",
+        "push r10",
+        "push r9",
+        "push r8",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rbx",
+        "push rax",
+
+        "mov rdi, rsp",
+        "call {handler}",
+
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+
+        // pop error code and int no
+        "add rsp, 16",
+
+        "swapgs",
+        "iretq",
+
+        ss_user = const SEL_USER_DATA,
+        cs_user = const SEL_USER_CODE,
+        handler = sym finish_full_return,
+        options(noreturn)
+    )
+}
+
+extern "C" fn needs_full_return() -> bool {
+    process::needs_full_user_return()
+}
+
+unsafe extern "C" fn finish_full_return(ctx: *mut InterruptContext) {
+    process::finish_user_return(&mut *ctx);
+}
+
+/// Called first by every syscall handler
+pub fn enter_syscall() {
+    scheduler::charge_current(CpuMode::User);
+}
+
+/// Called by every syscall handler just before returning to userspace
+pub fn leave_syscall() {
+    scheduler::preemption_point();
+    scheduler::charge_current(CpuMode::Kernel);
+}
+
+impl SyscallArg for u64 {
+    fn from_arg(arg: u64) -> Option<Self> {
+        Some(arg)
+    }
+}
+
+/// Fails if the upper 32 bits are set
+impl SyscallArg for u32 {
+    fn from_arg(arg: u64) -> Option<Self> {
+        u32::try_from(arg).ok()
+    }
+}
+
+impl<T: UserData> SyscallArg for UserPtr<T> {
+    fn from_arg(arg: u64) -> Option<Self> {
+        UserPtr::new(arg).ok()
+    }
+}
+
+/// Optional pointer, None if null
+impl<T: UserData> SyscallArg for Option<UserPtr<T>> {
+    fn from_arg(arg: u64) -> Option<Self> {
+        if arg == 0 {
+            Some(None)
+        } else {
+            UserPtr::new(arg).ok().map(Some)
+        }
+    }
+}
+
+/// 0 or 1
+impl SyscallArg for bool {
+    fn from_arg(arg: u64) -> Option<Self> {
+        match arg {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl SyscallArg for Resource {
+    fn from_arg(arg: u64) -> Option<Self> {
+        Resource::try_from(arg).ok()
+    }
+}
+
+/// FS (0) or GS (1)
+impl SyscallArg for UserSegment {
+    fn from_arg(arg: u64) -> Option<Self> {
+        match arg {
+            0 => Some(UserSegment::Fs),
+            1 => Some(UserSegment::Gs),
+            _ => None,
+        }
+    }
+}
+
+impl SyscallArg for SchedulingClass {
+    fn from_arg(arg: u64) -> Option<Self> {
+        u8::try_from(arg)
+            .ok()
+            .and_then(|class| SchedulingClass::try_from(class).ok())
+    }
+}
+
+/// Any value, lookups fail with [SyscallError::BadHandle] if it isn't open
+impl SyscallArg for Handle {
+    fn from_arg(arg: u64) -> Option<Self> {
+        Some(Handle::from(arg))
+    }
+}
+
+/// Optional handle, None if 0
+impl SyscallArg for Option<Handle> {
+    fn from_arg(arg: u64) -> Option<Self> {
+        if arg == 0 {
+            Some(None)
+        } else {
+            Some(Some(Handle::from(arg)))
+        }
+    }
+}
+
+impl SyscallArg for HandleRights {
+    fn from_arg(arg: u64) -> Option<Self> {
+        u32::try_from(arg).ok().and_then(HandleRights::from_bits)
+    }
+}
+
+impl SyscallArg for FilterAction {
+    fn from_arg(arg: u64) -> Option<Self> {
+        FilterAction::try_from(arg).ok()
+    }
+}
+
+impl From<SyscallResult> for SyscallReturn {
+    fn from(result: SyscallResult) -> Self {
+        Self { result, extra: 0 }
+    }
+}
+
+impl IntoSyscallReturn for Result<(), SyscallError> {
+    fn into_syscall_return(self) -> SyscallReturn {
+        SyscallResult::from(self).into()
+    }
+}
+
+impl IntoSyscallReturn for Result<u32, SyscallError> {
+    fn into_syscall_return(self) -> SyscallReturn {
+        SyscallResult::from(self).into()
+    }
+}
+
+/// Values that don't fit in 63 bits fail with [SyscallError::UnknownError]
+impl IntoSyscallReturn for Result<u64, SyscallError> {
+    fn into_syscall_return(self) -> SyscallReturn {
+        let result = match self {
+            Ok(val) => SyscallResult::try_ok(val)
+                .unwrap_or_else(|_| SyscallResult::error(SyscallError::UnknownError)),
+            Err(err) => SyscallResult::error(err),
+        };
+
+        result.into()
+    }
+}
+
+impl IntoSyscallReturn for Result<Pid, SyscallError> {
+    fn into_syscall_return(self) -> SyscallReturn {
+        self.map(u64::from).into_syscall_return()
+    }
+}
+
+impl IntoSyscallReturn for Result<Handle, SyscallError> {
+    fn into_syscall_return(self) -> SyscallReturn {
+        self.map(u64::from).into_syscall_return()
+    }
+}
+
+/// The second value is returned in rdx, and can use all 64 bits
+impl<T> IntoSyscallReturn for Result<(T, u64), SyscallError>
+where
+    Result<T, SyscallError>: IntoSyscallReturn,
+{
+    fn into_syscall_return(self) -> SyscallReturn {
+        match self {
+            Ok((first, extra)) => {
+                let ret = Ok(first).into_syscall_return();
+                if ret.result.is_ok() {
+                    SyscallReturn { extra, ..ret }
+                } else {
+                    ret
+                }
+            }
+            Err(err) => Err::<T, _>(err).into_syscall_return(),
+        }
+    }
+}
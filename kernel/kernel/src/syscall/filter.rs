@@ -0,0 +1,40 @@
+//! Per-process syscall filtering, to run untrusted programs with a restricted set of syscalls.
+//! Every syscall is checked against the calling process' [SyscallFilter] before its arguments are
+//! converted, and denied ones either fail with [SyscallError::PermissionDenied] or kill the process
+//! with [EXIT_CODE_SYSCALL_DENIED], depending on the filter's [FilterAction].
+//!
+//! Filters are inherited by children on creation and can only be tightened, with
+//! `set_syscall_filter` or by the parent before spawning.
+//!
+//! [SyscallFilter]: crate::process::SyscallFilter
+
+use crate::cpu::CpuState;
+use crate::process;
+use syscall::{FilterAction, SyscallError, SyscallNumber, EXIT_CODE_SYSCALL_DENIED};
+
+/// Fails if the calling process may not make the given syscall, or doesn't return if its filter
+/// kills it
+pub fn check(number: SyscallNumber) -> Result<(), SyscallError> {
+    let action = {
+        // safety: syscalls are called from thread context
+        let thread = unsafe { CpuState::current_thread() };
+        let process = thread.process();
+        let filter = process.syscall_filter();
+        if filter.is_allowed(number) {
+            return Ok(());
+        }
+
+        common::debug!(
+            "[{:?}:{:?}] {:?} denied by syscall filter",
+            process.pid(),
+            thread.tid(),
+            number
+        );
+        filter.action()
+    };
+
+    match action {
+        FilterAction::Deny => Err(SyscallError::PermissionDenied),
+        FilterAction::Kill => process::exit_current_process(EXIT_CODE_SYSCALL_DENIED),
+    }
+}
@@ -1,123 +1,68 @@
-use crate::cpu::CpuState;
-use core::ffi::c_void;
-use memory::VIRT_USERSPACE_MAX;
-use syscall::{SyscallError, SyscallResult};
-
-const COUNT: usize = 1;
-static TRAMPOLINES: [unsafe extern "C" fn() -> !; COUNT] = [tramp_validate_log];
-
-#[naked]
-pub unsafe extern "C" fn syscall_entry() -> ! {
-    const ERR_INVALID: u64 = SyscallResult::error(SyscallError::InvalidSyscall).to_u64();
-
-    asm!(
-        // rax=syscall number
-        // rcx=rip to return to, must be restored before sysret
-        // r11=rflags
-
-        // make kernel structures available
-        "swapgs",
-
-        // check syscall number is valid
-        "cmp rax, {syscall_count}", // TODO works with negative too?
-        "jae 1f",
-
-        // jmp to syscall trampoline, clobbering r10
-        "mov r10, {trampoline_base}",
-        "add r10, rax",
-        "jmp r10",
-
-        // bad syscall
-        "1: mov rax, {err_invalid}",
-        "swapgs",
-        "sysretq",
-
-        trampoline_base = sym TRAMPOLINES,
-        syscall_count = const COUNT,
-        err_invalid = const ERR_INVALID,
-        options(noreturn)
-    )
-}
+//! Syscall handlers. See README.md for the calling convention and the list of syscalls, which is
+//! defined here by [syscalls].
 
-// TODO autogenerate trampolines based on number of args
-// TODO reshuffle needed for args 2 and 3 (rbx->rdx, rdx->rcx (backing up rcx to e.g. r10?)
-#[naked]
-unsafe extern "C" fn tramp_validate_log() -> ! {
-    const ERR_ARGS: u64 = SyscallResult::error(SyscallError::InvalidSyscall).to_u64();
-    asm!(
-        // rdi = pointer value
-        "mov r10, {userspace_max}",
-        "cmp rdi, r10",
-        "jae 1f",
-
-        // rsi = length
-        // bounds check length of string (r10 = max-(str+len))
-        "sub r10, rdi",
-        "sub r10, rsi",
-        "js 1f", // error if negative, sf=1
-
-        // args validated! switch to kernel stack
-        "mov r10, rsp",
-        "mov rsp, gs:{gs_stack_offset}",
-
-        // preserve values
-        // TODO r11 for user flags too
-        "push rcx", // user rip
-        "push r10", // user rsp
-
-        // call C ABI syscall handler
-        // TODO preserve registers for C ABI
-        "call {handler}",
-        "jmp {success_return}",
-
-        // bad args. we can sysret immediately without any other reg restoring required because
-        // no other registers have been clobbered or functions called
-        "1: mov rax, {err_args}",
-        "swapgs",
-        "sysretq",
-
-        userspace_max = const VIRT_USERSPACE_MAX,
-        gs_stack_offset = const CpuState::THREAD_KERNEL_STACK_OFFSET,
-        handler = sym tramp_to_rust_log,
-        success_return = sym common_return,
-        err_args = const ERR_ARGS,
-        options(noreturn)
-    )
-}
+#[macro_use]
+mod dispatch;
+mod filter;
+mod trace;
 
-/// Returns to userspace after a syscall handler has run
-///
-/// Assumptions:
-/// * swapgs has been run once
-/// * we're on kernel stack of current thread
-/// * stack is (top-->bottom) [ user rsp, user rip ]
-#[naked]
-unsafe extern "C" fn common_return() -> ! {
-    asm!(
-        "pop r10", // user rsp
-        "pop rcx", // user rip
-        // restore user stack
-        "mov rsp, r10",
-        // back we go
-        "swapgs",
-        "sysretq",
-        options(noreturn)
-    )
-}
+use crate::cpu::CpuState;
+use crate::memory::{UserPtr, UserSlice};
+use crate::process::{
+    self, new_pid, ExitCode, Handle, KernelObject, Pid, Resource, ThreadProcess, ThreadRef,
+    UserApc, UserSegment, UNLIMITED,
+};
+use crate::scheduler::{self, SchedulingClass, ThreadPriority};
+use crate::sync::futex;
+use crate::{clock, rtc};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU32, Ordering};
+use memory::{PhysicalAddress, VirtualAddress, VIRT_USERSPACE_MAX};
+use syscall::{
+    FilterAction, HandleRights, SpawnParameters, SyscallError, MAX_COMMAND_LINE_LEN,
+    MAX_ENVIRONMENT_LEN, MAX_IMAGE_NAME_LEN,
+};
 
-/// Trampoline through C ABI to Rust ABI of syscall handler
-unsafe extern "C" fn tramp_to_rust_log(string: *mut c_void, len: u64) -> SyscallResult {
-    // call rust abi handler
-    let res = syscall_log(string, len);
+pub use dispatch::syscall_entry;
+pub use trace::set_global_tracing;
 
-    // return value in rax
-    SyscallResult::from(res)
+syscalls! {
+    Log => syscall_log(string: u64, len: u64);
+    Exit => syscall_exit(exit_code: u64);
+    Wait => syscall_wait(process: Option<Handle>, exit_code: Option<UserPtr<ExitCode>>);
+    GetPriority => syscall_get_priority(thread: Option<Handle>);
+    SetPriority => syscall_set_priority(thread: Option<Handle>, class: SchedulingClass, level: u64);
+    SetSegmentBase => syscall_set_segment_base(segment: UserSegment, base: u64);
+    GetCpuTime => syscall_get_cpu_time(scope: u64, mode: u64);
+    GetResourceLimit => syscall_get_resource_limit(resource: Resource);
+    SetResourceLimit => syscall_set_resource_limit(resource: Resource, limit: u64);
+    QueueApc => syscall_queue_apc(thread: Option<Handle>, routine: u64, arg: u64);
+    ApcReturn => syscall_apc_return(frame: u64);
+    CreateThread => syscall_create_thread(entry: u64, arg: u64);
+    ExitThread => syscall_exit_thread(exit_value: u64);
+    JoinThread => syscall_join_thread(thread: Handle, exit_value: Option<UserPtr<u64>>);
+    Spawn => syscall_spawn(params: UserPtr<SpawnParameters>);
+    SetSyscallTracing => syscall_set_syscall_tracing(scope: u64, enabled: bool);
+    GetTime => syscall_get_time(clock_id: u64);
+    SleepUntil => syscall_sleep_until(deadline_ns: u64);
+    SleepFor => syscall_sleep_for(duration_ns: u64);
+    CloseHandle => syscall_close_handle(handle: Handle);
+    DuplicateHandle => syscall_duplicate_handle(handle: Handle, rights: HandleRights);
+    OpenProcess => syscall_open_process(pid: u64, rights: HandleRights);
+    OpenThread => syscall_open_thread(tid: u64, rights: HandleRights);
+    SetSyscallFilter => syscall_set_syscall_filter(scope: u64, allowed: u64, action: FilterAction);
+    FutexWait => syscall_futex_wait(addr: UserPtr<u32>, expected: u32);
+    FutexWake => syscall_futex_wake(addr: UserPtr<u32>, count: u64);
 }
 
-fn syscall_log(string: *mut c_void, len: u64) -> Result<(), SyscallError> {
-    // safety: verified by syscall handler
-    let slice = unsafe { core::slice::from_raw_parts(string as *mut u8, len as usize) };
-    match core::str::from_utf8(slice) {
+/// Longest message accepted by `log`, in bytes
+const MAX_LOG_LEN: u64 = 4096;
+
+fn syscall_log(string: u64, len: u64) -> Result<(), SyscallError> {
+    let bytes = UserSlice::new(string, len)?.read_to_vec(MAX_LOG_LEN)?;
+    match core::str::from_utf8(&bytes) {
         Ok(s) => {
             common::info!("message from userspace: '{}'", s);
             Ok(())
@@ -128,3 +73,434 @@ fn syscall_log(string: *mut c_void, len: u64) -> Result<(), SyscallError> {
         }
     }
 }
+
+/// Exits the calling process, only the lower 32 bits of the code are kept. Never returns
+fn syscall_exit(exit_code: u64) -> Result<(), SyscallError> {
+    crate::process::exit_current_process(exit_code as ExitCode)
+}
+
+/// Waits for the child the handle refers to, which needs [HandleRights::WAIT], or any child if 0, to
+/// exit, and returns its pid and its exit code in rdx. The exit code is also written to `exit_code`
+/// if not null
+fn syscall_wait(
+    process: Option<Handle>,
+    exit_code: Option<UserPtr<ExitCode>>,
+) -> Result<(Pid, u64), SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let pid = match process {
+        Some(handle) => Some(
+            current
+                .process()
+                .process_handle(handle, HandleRights::WAIT)?
+                .pid(),
+        ),
+        None => None,
+    };
+
+    let (child, code) = current.process().wait_for_child(pid).map_err(|err| {
+        common::debug!("wait failed: {}", err);
+        SyscallError::from(err)
+    })?;
+
+    if let Some(exit_code) = exit_code {
+        exit_code.write(&code)?;
+    }
+
+    Ok((child, u64::from(code)))
+}
+
+/// Current thread if 0, otherwise a thread in the calling process
+fn thread_in_current_process(tid: u64) -> Result<ThreadRef, SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    if tid == 0 {
+        return Ok(current);
+    }
+
+    match crate::process::thread_by_tid(Pid::from(tid)) {
+        Some(thread) if thread.process().pid() == current.process().pid() => Ok(thread),
+        _ => Err(SyscallError::NotFound),
+    }
+}
+
+/// The thread the handle refers to, which needs `required` rights, or the calling thread if None
+fn thread_by_handle(
+    thread: Option<Handle>,
+    required: HandleRights,
+) -> Result<ThreadRef, SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    match thread {
+        Some(handle) => current
+            .process()
+            .thread_handle(handle, required)
+            .map_err(SyscallError::from),
+        None => Ok(current),
+    }
+}
+
+/// Returns `class << 8 | level` of the thread the handle refers to, which needs
+/// [HandleRights::QUERY], or of the calling thread if 0
+fn syscall_get_priority(thread: Option<Handle>) -> Result<u32, SyscallError> {
+    let thread = thread_by_handle(thread, HandleRights::QUERY)?;
+    Ok(thread.priority().to_u32())
+}
+
+/// Sets the priority of the thread the handle refers to, which needs [HandleRights::MODIFY], or of
+/// the calling thread if 0. Userspace can't use the realtime class
+fn syscall_set_priority(
+    thread: Option<Handle>,
+    class: SchedulingClass,
+    level: u64,
+) -> Result<(), SyscallError> {
+    if class == SchedulingClass::Realtime {
+        return Err(SyscallError::PermissionDenied);
+    }
+
+    let priority = u8::try_from(level)
+        .ok()
+        .and_then(|level| ThreadPriority::new(class, level))
+        .ok_or(SyscallError::InvalidArguments)?;
+
+    let thread = thread_by_handle(thread, HandleRights::MODIFY)?;
+    scheduler::set_priority(&thread, priority);
+    Ok(())
+}
+
+/// Sets the FS or GS base of the calling thread, e.g. for thread-local storage
+fn syscall_set_segment_base(segment: UserSegment, base: u64) -> Result<(), SyscallError> {
+    // must be canonical or the MSR write faults
+    if base >= VIRT_USERSPACE_MAX {
+        return Err(SyscallError::BadAddress);
+    }
+
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+
+    // safety: current thread in the kernel, user gs has been swapped out
+    unsafe { current.set_user_segment_base(segment, base) };
+    Ok(())
+}
+
+/// Nanoseconds of CPU time used by the calling thread (scope 0) or its process (scope 1), in user
+/// (mode 0) or kernel (mode 1) mode
+fn syscall_get_cpu_time(scope: u64, mode: u64) -> Result<u64, SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+
+    let cpu_time = match scope {
+        0 => current.cpu_time(),
+        1 => current.process().cpu_time(),
+        _ => return Err(SyscallError::InvalidArguments),
+    };
+
+    match mode {
+        0 => Ok(cpu_time.user_ns()),
+        1 => Ok(cpu_time.kernel_ns()),
+        _ => Err(SyscallError::InvalidArguments),
+    }
+}
+
+/// Limit of the given resource for the calling process, [UNLIMITED] if none
+fn syscall_get_resource_limit(resource: Resource) -> Result<u64, SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    Ok(current.process().limits().limit(resource))
+}
+
+/// Lowers the limit of the given resource for the calling process and any children it creates
+/// afterwards. Limits can't be raised
+fn syscall_set_resource_limit(resource: Resource, limit: u64) -> Result<(), SyscallError> {
+    let limit = limit.min(UNLIMITED);
+
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    current
+        .process()
+        .limits()
+        .lower(resource, limit)
+        .map_err(|err| {
+            common::debug!("set_resource_limit failed: {}", err);
+            SyscallError::from(err)
+        })
+}
+
+/// Queues `routine(arg, frame)` to run on the thread in the calling process the handle refers to,
+/// which needs [HandleRights::MODIFY], or the calling thread if 0, the next time it returns to
+/// userspace
+fn syscall_queue_apc(thread: Option<Handle>, routine: u64, arg: u64) -> Result<(), SyscallError> {
+    if routine == 0 || routine >= VIRT_USERSPACE_MAX {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let thread = thread_by_handle(thread, HandleRights::MODIFY)?;
+    // the routine is only mapped in the calling process
+    // safety: syscalls are called from thread context
+    if thread.process().pid() != unsafe { CpuState::current_thread() }.process().pid() {
+        return Err(SyscallError::NotFound);
+    }
+
+    thread.queue_user_apc(UserApc { routine, arg });
+    Ok(())
+}
+
+/// Resumes the context saved in the given APC frame on return, then enters the next pending APC
+/// if any. Doesn't return to the caller on success
+fn syscall_apc_return(frame: u64) -> Result<(), SyscallError> {
+    process::begin_apc_return(frame)
+}
+
+/// Starts a new thread in the calling process at `entry`, with `arg` in rcx, and returns its tid
+fn syscall_create_thread(entry: u64, arg: u64) -> Result<Pid, SyscallError> {
+    if entry == 0 || entry >= VIRT_USERSPACE_MAX {
+        return Err(SyscallError::BadAddress);
+    }
+
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let thread = ThreadRef::new(
+        ThreadProcess::Process(current.process().clone()),
+        new_pid(),
+        VirtualAddress::new(entry),
+        arg,
+    )
+    .map_err(|err| {
+        common::debug!("create_thread failed: {}", err);
+        SyscallError::from(err)
+    })?;
+
+    scheduler::wake(&thread);
+    Ok(thread.tid())
+}
+
+/// Exits the calling thread, which can then be joined to retrieve `exit_value`. The process exits
+/// with code 0 if it was the last thread. Never returns
+fn syscall_exit_thread(exit_value: u64) -> Result<(), SyscallError> {
+    crate::process::exit_current_thread(exit_value)
+}
+
+/// Waits for the thread in the calling process the handle refers to, which needs
+/// [HandleRights::WAIT], to exit, then forgets it. Its exit value is returned in rdx, and also
+/// written to `exit_value` if not null
+fn syscall_join_thread(
+    thread: Handle,
+    exit_value: Option<UserPtr<u64>>,
+) -> Result<((), u64), SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let tid = current
+        .process()
+        .thread_handle(thread, HandleRights::WAIT)?
+        .tid();
+    if tid == current.tid() {
+        // would never return
+        return Err(SyscallError::InvalidArguments);
+    }
+
+    let value = current.process().join_thread(tid).map_err(|err| {
+        common::debug!("join_thread failed: {}", err);
+        SyscallError::from(err)
+    })?;
+
+    if let Some(exit_value) = exit_value {
+        exit_value.write(&value)?;
+    }
+
+    Ok(((), value))
+}
+
+/// Starts a child process of the caller running the named executable, and returns its pid to wait
+/// on
+fn syscall_spawn(params: UserPtr<SpawnParameters>) -> Result<Pid, SyscallError> {
+    let params = params.read()?;
+
+    // copied so other threads in the caller can't change them from under us
+    let image_name = user_string(params.image_name, params.image_name_len, MAX_IMAGE_NAME_LEN)?;
+    let command_line = user_string(
+        params.command_line,
+        params.command_line_len,
+        MAX_COMMAND_LINE_LEN,
+    )?;
+    let environment_block = user_string(
+        params.environment,
+        params.environment_len,
+        MAX_ENVIRONMENT_LEN,
+    )?;
+    let environment = syscall::iter_environment_block(&environment_block).collect::<Vec<_>>();
+
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let (process, thread) =
+        process::spawn_process(current.process(), &image_name, &command_line, &environment)
+            .map_err(|err| {
+                common::debug!("spawn of {:?} failed: {}", image_name, err);
+                SyscallError::from(err)
+            })?;
+
+    scheduler::wake(&thread);
+    Ok(process.pid())
+}
+
+/// Enables or disables syscall tracing for the calling process (scope 0) and children it creates
+/// afterwards, or for all processes (scope 1), which only the kernel process can change
+fn syscall_set_syscall_tracing(scope: u64, enabled: bool) -> Result<(), SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    match scope {
+        0 => current.process().set_trace_syscalls(enabled),
+        1 if current.process().privilege_level().is_user() => {
+            return Err(SyscallError::PermissionDenied)
+        }
+        1 => set_global_tracing(enabled),
+        _ => return Err(SyscallError::InvalidArguments),
+    }
+
+    Ok(())
+}
+
+/// Nanoseconds since boot (clock 0) or since 1970-01-01 00:00 UTC (clock 1)
+fn syscall_get_time(clock_id: u64) -> Result<u64, SyscallError> {
+    match clock_id {
+        0 => Ok(clock::since_boot().as_nanos() as u64),
+        1 => Ok(rtc::unix_ns()),
+        _ => Err(SyscallError::InvalidArguments),
+    }
+}
+
+/// Blocks the calling thread until the given nanoseconds since boot, returning immediately if
+/// that has already passed
+fn syscall_sleep_until(deadline_ns: u64) -> Result<(), SyscallError> {
+    scheduler::sleep_until(deadline_ns);
+    Ok(())
+}
+
+/// Blocks the calling thread for at least the given number of nanoseconds
+fn syscall_sleep_for(duration_ns: u64) -> Result<(), SyscallError> {
+    let deadline = clock::monotonic_ns().saturating_add(duration_ns);
+    scheduler::sleep_until(deadline);
+    Ok(())
+}
+
+fn syscall_close_handle(handle: Handle) -> Result<(), SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    current
+        .process()
+        .close_handle(handle)
+        .map_err(SyscallError::from)
+}
+
+/// Opens another handle to the same object with a subset of the original's rights, which must
+/// include [HandleRights::DUPLICATE]
+fn syscall_duplicate_handle(handle: Handle, rights: HandleRights) -> Result<Handle, SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    current
+        .process()
+        .duplicate_handle(handle, rights)
+        .map_err(|err| {
+            common::debug!("duplicate_handle failed: {}", err);
+            SyscallError::from(err)
+        })
+}
+
+/// Opens a handle to the calling process if pid is 0, otherwise one of its unreaped children
+fn syscall_open_process(pid: u64, rights: HandleRights) -> Result<Handle, SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let process = current.process();
+
+    let target = if pid == 0 {
+        process.clone()
+    } else {
+        process
+            .child(Pid::from(pid))
+            .ok_or(SyscallError::NotFound)?
+    };
+
+    process
+        .open_handle(KernelObject::Process(target), rights)
+        .map_err(SyscallError::from)
+}
+
+/// Opens a handle to a thread in the calling process, or the calling thread if 0
+fn syscall_open_thread(tid: u64, rights: HandleRights) -> Result<Handle, SyscallError> {
+    let thread = thread_in_current_process(tid)?;
+
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    current
+        .process()
+        .open_handle(KernelObject::Thread(thread), rights)
+        .map_err(SyscallError::from)
+}
+
+/// Restricts the syscalls the calling process and children it creates afterwards (scope 0), or only
+/// children it creates afterwards (scope 1), can make to those with their bit set in `allowed`.
+/// Filters can only be tightened, so syscalls already denied stay denied
+fn syscall_set_syscall_filter(
+    scope: u64,
+    allowed: u64,
+    action: FilterAction,
+) -> Result<(), SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let process = current.process();
+
+    let filter = match scope {
+        0 => process.syscall_filter(),
+        1 => process.child_syscall_filter(),
+        _ => return Err(SyscallError::InvalidArguments),
+    };
+
+    filter.tighten(allowed, action);
+    Ok(())
+}
+
+/// Blocks the calling thread until woken by `futex_wake` on the same word, if the word at `addr`
+/// still equals `expected`. Fails with [SyscallError::WouldBlock] if it doesn't, and may return
+/// spuriously, so callers should recheck the word in a loop
+fn syscall_futex_wait(addr: UserPtr<u32>, expected: u32) -> Result<(), SyscallError> {
+    let phys = futex_address(addr)?;
+
+    // safety: aligned so doesn't cross a page, and the frame is always accessible through the
+    // identity mapping
+    let word = unsafe { &*VirtualAddress::from_physical(phys).as_ptr::<AtomicU32>() };
+
+    if futex::wait(phys, || word.load(Ordering::SeqCst) == expected) {
+        Ok(())
+    } else {
+        Err(SyscallError::WouldBlock)
+    }
+}
+
+/// Wakes up to `count` threads waiting on the word at `addr` in any process, and returns how many
+/// were woken
+fn syscall_futex_wake(addr: UserPtr<u32>, count: u64) -> Result<u64, SyscallError> {
+    let phys = futex_address(addr)?;
+    let count = usize::try_from(count).unwrap_or(usize::MAX);
+    Ok(futex::wake(phys, count) as u64)
+}
+
+/// Physical address of a futex word in the calling process, which is shared by all processes
+/// mapping the same memory
+fn futex_address(addr: UserPtr<u32>) -> Result<PhysicalAddress, SyscallError> {
+    // safety: syscalls are called from thread context
+    let current = unsafe { CpuState::current_thread() };
+    let phys = current
+        .process()
+        .address_space()
+        .resolve_physical(addr.address());
+
+    phys.map_err(|err| {
+        common::debug!("futex address {:?} not mapped: {}", addr, err);
+        SyscallError::BadAddress
+    })
+}
+
+/// Copies a UTF-8 string of at most `max_len` bytes from userspace, which may be empty
+fn user_string(addr: u64, len: u64, max_len: u64) -> Result<String, SyscallError> {
+    let bytes = UserSlice::new(addr, len)?.read_to_vec(max_len)?;
+    String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArguments)
+}
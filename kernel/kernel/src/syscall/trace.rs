@@ -0,0 +1,113 @@
+//! Logging of syscalls as they are made, like strace. Enabled for all processes with
+//! [set_global_tracing], e.g. by `strace` on the kernel command line, or per process with
+//! [ProcessRef::set_trace_syscalls](crate::process::ProcessRef::set_trace_syscalls).
+//!
+//! Each traced syscall logs its decoded arguments on entry, and its result and duration on exit.
+//! Syscalls that block are logged in two parts so they can be followed while blocked, and those
+//! that never return, like `exit`, only log their entry.
+
+use crate::clock;
+use crate::cpu::CpuState;
+use crate::process::Pid;
+use crate::syscall::dispatch::SyscallReturn;
+use core::fmt::Debug;
+use core::sync::atomic::{AtomicBool, Ordering};
+use syscall::SyscallNumber;
+
+static TRACE_ALL: AtomicBool = AtomicBool::new(false);
+
+/// Traces syscalls made by every process, in addition to those with tracing enabled individually
+pub fn set_global_tracing(enabled: bool) {
+    TRACE_ALL.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_global_tracing() -> bool {
+    TRACE_ALL.load(Ordering::Relaxed)
+}
+
+/// A syscall being traced, from [begin](Self::begin) to [end](Self::end)
+pub struct SyscallTrace {
+    number: SyscallNumber,
+    pid: Pid,
+    tid: Pid,
+    start_ns: u64,
+}
+
+impl SyscallTrace {
+    /// None if the calling process isn't traced
+    pub fn begin(number: SyscallNumber) -> Option<Self> {
+        // safety: syscalls are called from thread context
+        let thread = unsafe { CpuState::current_thread() };
+        let process = thread.process();
+        if !(is_global_tracing() || process.traces_syscalls()) {
+            return None;
+        }
+
+        Some(Self {
+            number,
+            pid: process.pid(),
+            tid: thread.tid(),
+            start_ns: clock::monotonic_ns(),
+        })
+    }
+
+    /// Logs the arguments as converted for the handler
+    pub fn log_args(&self, args: &[(&str, &dyn Debug)]) {
+        struct Args<'a>(&'a [(&'a str, &'a dyn Debug)]);
+
+        impl Debug for Args<'_> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                for (i, (name, value)) in self.0.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {:?}", name, value)?;
+                }
+                Ok(())
+            }
+        }
+
+        common::info!(
+            "[{:?}:{:?}] {:?}({:?})",
+            self.pid,
+            self.tid,
+            self.number,
+            Args(args)
+        );
+    }
+
+    /// Logs the raw argument registers, when they couldn't be converted for the handler
+    pub fn log_raw_args(&self, args: &[u64]) {
+        common::info!(
+            "[{:?}:{:?}] {:?}({:x?}) invalid arguments",
+            self.pid,
+            self.tid,
+            self.number,
+            args
+        );
+    }
+
+    /// Logs the raw argument registers of a syscall denied by the process' syscall filter
+    pub fn log_denied(&self, args: &[u64]) {
+        common::info!(
+            "[{:?}:{:?}] {:?}({:x?}) denied by syscall filter",
+            self.pid,
+            self.tid,
+            self.number,
+            args
+        );
+    }
+
+    pub fn end(self, ret: SyscallReturn) {
+        let duration_ns = clock::monotonic_ns().saturating_sub(self.start_ns);
+        common::info!(
+            "[{:?}:{:?}] {:?} = {:?}, {:#x} in {}ns",
+            self.pid,
+            self.tid,
+            self.number,
+            ret.result,
+            ret.extra,
+            duration_ns
+        );
+    }
+}
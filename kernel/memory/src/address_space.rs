@@ -109,6 +109,9 @@ impl<'p, M: MemoryProvider> RawAddressSpace<'p, M> {
 
     // TODO constructor to allocate new possibly unmapped frame for p4, then access through id map
 
+    /// The returned slice is only accessible if this address space is current, otherwise use
+    /// [write_bytes](Self::write_bytes) to populate the mapping.
+    ///
     /// * size: bytes
     pub fn map_range(
         &mut self,
@@ -395,6 +398,158 @@ impl<'p, M: MemoryProvider> RawAddressSpace<'p, M> {
         ptr.map(|(level, ptr)| (level, unsafe { &mut *ptr }))
     }
 
+    /// Copies `src` to `addr` in this address space through the physical identity mapping, so
+    /// works whether or not this address space is current. Pages mapped on demand are allocated
+    pub fn write_bytes(&mut self, addr: VirtualAddress, src: &[u8]) -> MemoryResult<()> {
+        self.for_each_physical_chunk(addr, src.len() as u64, |offset, dst| {
+            dst.copy_from_slice(&src[offset..offset + dst.len()]);
+        })
+    }
+
+    /// Sets `len` bytes from `addr` to `byte`, as [write_bytes](Self::write_bytes)
+    pub fn fill_bytes(&mut self, addr: VirtualAddress, len: u64, byte: u8) -> MemoryResult<()> {
+        self.for_each_physical_chunk(addr, len, |_, dst| dst.fill(byte))
+    }
+
+    /// Copies from `addr` in this address space into `dst`, as [write_bytes](Self::write_bytes)
+    pub fn read_bytes(&mut self, addr: VirtualAddress, dst: &mut [u8]) -> MemoryResult<()> {
+        self.for_each_physical_chunk(addr, dst.len() as u64, |offset, src| {
+            dst[offset..offset + src.len()].copy_from_slice(src);
+        })
+    }
+
+    /// Physical address that `addr` in this address space is backed by, which is the same for
+    /// every address space sharing the frame. Pages mapped on demand are allocated
+    pub fn resolve_physical(&mut self, addr: VirtualAddress) -> MemoryResult<PhysicalAddress> {
+        let frame = self.resolve_frame(addr)?;
+        Ok(frame + addr.address() % FRAME_SIZE)
+    }
+
+    /// Calls `f` with (offset from `start`, identity mapped slice) for each page-bounded chunk of
+    /// the given range
+    fn for_each_physical_chunk(
+        &mut self,
+        start: VirtualAddress,
+        len: u64,
+        mut f: impl FnMut(usize, &mut [u8]),
+    ) -> MemoryResult<()> {
+        let mut offset = 0;
+        while offset < len {
+            let addr = start + offset;
+            let page_offset = addr.address() % FRAME_SIZE;
+            let chunk_len = (FRAME_SIZE - page_offset).min(len - offset);
+
+            let frame = self.resolve_frame(addr)?;
+            let virt = VirtualAddress::from_physical(frame + page_offset);
+
+            // safety: frame is mapped in this address space and accessible through the identity
+            // mapping, and the chunk doesn't cross the frame boundary
+            let chunk =
+                unsafe { core::slice::from_raw_parts_mut(virt.as_ptr(), chunk_len as usize) };
+            f(offset as usize, chunk);
+
+            offset += chunk_len;
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps every 4K page in the given range, including those mapped on demand but never
+    /// accessed. The frame backing each present page is passed to `free`, and pages that aren't
+    /// mapped are skipped. Page tables are kept, and the caller must flush the TLB if this address
+    /// space is current
+    pub fn unmap_range(
+        &mut self,
+        start: VirtualAddress,
+        size: u64,
+        mut free: impl FnMut(PhysicalAddress),
+    ) -> MemoryResult<()> {
+        let start = start.round_down_to(FRAME_SIZE);
+        let mut offset = 0;
+        while offset < size {
+            let addr = start + offset;
+            offset += FRAME_SIZE;
+
+            let entry = match Self::page_entry(&mut self.pml4, addr) {
+                Ok(entry) => entry,
+                Err(MemoryError::NotMapped(_)) => continue,
+                Err(err) => return Err(err),
+            };
+
+            if entry.present() {
+                free(entry.address());
+            } else if entry.as_custom().is_none() {
+                continue;
+            }
+
+            entry.replace().apply();
+        }
+
+        Ok(())
+    }
+
+    /// Physical frame backing the 4K page containing `addr`, allocated now if the page is mapped
+    /// on demand
+    fn resolve_frame(&mut self, addr: VirtualAddress) -> MemoryResult<PhysicalAddress> {
+        let not_mapped = || MemoryError::NotMapped(addr.address());
+
+        let entry = Self::page_entry(&mut self.pml4, addr)?;
+        if entry.present() {
+            return Ok(entry.address());
+        }
+
+        let mapping = entry.as_custom_mut().ok_or_else(not_mapped)?;
+        if !matches!(mapping.on_demand(), DemandMapping::Anonymous) {
+            return Err(not_mapped());
+        }
+
+        let frame = self.memory.new_frame()?;
+        frame.zero();
+
+        mapping
+            .as_builder()
+            .address(frame.address())
+            .present()
+            .apply();
+
+        Ok(frame.address())
+    }
+
+    /// Level 1 entry for the 4K page containing `addr`, which may not be present. Fails with
+    /// `NotMapped` if there is no page table for it
+    fn page_entry(
+        pml4: &mut P4<'p>,
+        addr: VirtualAddress,
+    ) -> MemoryResult<&'p mut CommonEntry<'p, Frame>> {
+        let (p4_idx, p3_idx, p2_idx, p1_idx) = (
+            addr.pml4t_offset(),
+            addr.pdp_offset(),
+            addr.pd_offset(),
+            addr.pt_offset(),
+        );
+
+        // TODO support huge pages and big absent pages
+        let not_mapped = || MemoryError::NotMapped(addr.address());
+
+        use Either::*;
+        let mut p1 = match Self::get_existing_entry(pml4, p4_idx)? {
+            Left(mut p3) => match Self::get_existing_entry(&mut p3, p3_idx)? {
+                Left(mut p2) => match Self::get_existing_entry(&mut p2, p2_idx)? {
+                    Left(p1) => p1,
+                    Right(_) => return Err(not_mapped()),
+                },
+                Right(_) => return Err(not_mapped()),
+            },
+            Right(_) => return Err(not_mapped()),
+        };
+
+        let entry = p1.table_mut()?.entry_mut(p1_idx) as *mut CommonEntry<'p, Frame>;
+
+        // safety: cant return reference from "borrowed" page tables, but everything lives in
+        // physical memory and so is actually present still
+        Ok(unsafe { &mut *entry })
+    }
+
     /// Unmapped page returned as `Ok(Either::Right())`
     fn get_unmapped_entry<P, N>(table: &mut P, idx: u16) -> MemoryResult<Either<N, ()>>
     where
@@ -746,7 +901,7 @@ impl<'p, M> PartialEq<P4<'p>> for RawAddressSpace<'p, M> {
 mod tests {
     use super::*;
     use crate::address::{PhysicalAddress, VirtualAddress};
-    use crate::{PageTable, PhysicalFrame, FRAME_SIZE, P4};
+    use crate::{round_up_to, PageTable, PhysicalFrame, FRAME_SIZE, P3, P4};
 
     const FRAME_COUNT: usize = 4096;
     struct Memory {
@@ -756,8 +911,9 @@ mod tests {
 
     impl Memory {
         fn new() -> Self {
+            // an extra frame so they can all be aligned
             Self {
-                pages: vec![0u8; FRAME_COUNT * FRAME_SIZE as usize].into_boxed_slice(),
+                pages: vec![0u8; (FRAME_COUNT + 1) * FRAME_SIZE as usize].into_boxed_slice(),
                 next: 0,
             }
         }
@@ -769,11 +925,243 @@ mod tests {
             assert!(idx < FRAME_COUNT, "all gone");
             self.next += 1;
 
-            let frame = &self.pages[idx * FRAME_SIZE as usize..];
-            unsafe { Ok(PhysicalFrame::new(PhysicalAddress(frame.as_ptr() as u64))) }
+            // page table entries can only point to aligned frames
+            let base = round_up_to(self.pages.as_ptr() as u64, FRAME_SIZE);
+            let frame = base + idx as u64 * FRAME_SIZE;
+            unsafe { Ok(PhysicalFrame::new(PhysicalAddress(frame))) }
         }
     }
 
+    fn new_space<'p>(p4: &'p mut PageTable<'p, P3<'p>>) -> RawAddressSpace<'p, Memory> {
+        unsafe { RawAddressSpace::with_existing(P4::with_initialized(p4), Memory::new()) }
+    }
+
+    /// Bytes at a physical address, through the identity mapping
+    fn physical_bytes(addr: PhysicalAddress, len: usize) -> &'static [u8] {
+        let virt = VirtualAddress::from_physical(addr);
+        unsafe { core::slice::from_raw_parts(virt.as_ptr(), len) }
+    }
+
+    #[test]
+    fn copying_bytes() {
+        let mut p4 = PageTable::default();
+        let mut space = new_space(&mut p4);
+
+        let start = VirtualAddress::with_literal(0x10000);
+        space
+            .map_range(start, 0x3000, MapTarget::Any, MapFlags::Commit)
+            .expect("mapping failed");
+
+        // crosses two page boundaries
+        let addr = VirtualAddress::with_literal(0x10800);
+        let src = (0..0x1900).map(|i| i as u8).collect::<Vec<_>>();
+        space.write_bytes(addr, &src).expect("write failed");
+
+        // each chunk lands in the frame for its page
+        let first = space.resolve_physical(addr).unwrap();
+        let second = space
+            .resolve_physical(VirtualAddress::with_literal(0x11000))
+            .unwrap();
+        let third = space
+            .resolve_physical(VirtualAddress::with_literal(0x12000))
+            .unwrap();
+        assert_eq!(physical_bytes(first, 0x800), &src[..0x800]);
+        assert_eq!(physical_bytes(second, 0x1000), &src[0x800..0x1800]);
+        assert_eq!(physical_bytes(third, 0x100), &src[0x1800..]);
+
+        let mut dst = vec![0u8; src.len()];
+        space.read_bytes(addr, &mut dst).expect("read failed");
+        assert_eq!(dst, src);
+
+        // untouched either side
+        let mut edges = [0xffu8; 2];
+        space
+            .read_bytes(addr - 1, &mut edges[..1])
+            .expect("read failed");
+        space
+            .read_bytes(addr + src.len() as u64, &mut edges[1..])
+            .expect("read failed");
+        assert_eq!(edges, [0, 0]);
+
+        // empty is fine anywhere
+        space
+            .read_bytes(VirtualAddress::with_literal(0x4000_0000), &mut [])
+            .expect("read failed");
+    }
+
+    #[test]
+    fn filling_bytes() {
+        let mut p4 = PageTable::default();
+        let mut space = new_space(&mut p4);
+
+        let start = VirtualAddress::with_literal(0x10000);
+        space
+            .map_range(start, 0x2000, MapTarget::Any, MapFlags::Commit)
+            .expect("mapping failed");
+
+        space
+            .fill_bytes(start + 0xff0, 0x20, 0xaa)
+            .expect("fill failed");
+
+        let mut dst = [0u8; 0x40];
+        space
+            .read_bytes(start + 0xfe0, &mut dst)
+            .expect("read failed");
+        assert!(dst[..0x10].iter().all(|b| *b == 0));
+        assert!(dst[0x10..0x30].iter().all(|b| *b == 0xaa));
+        assert!(dst[0x30..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn accessing_unmapped() {
+        let mut p4 = PageTable::default();
+        let mut space = new_space(&mut p4);
+
+        let start = VirtualAddress::with_literal(0x10000);
+        space
+            .map_range(start, 0x1000, MapTarget::Any, MapFlags::Commit)
+            .expect("mapping failed");
+
+        // no page tables at all
+        let far = VirtualAddress::with_literal(0x4000_0000);
+        assert!(matches!(
+            space.read_bytes(far, &mut [0u8; 4]),
+            Err(MemoryError::NotMapped(_))
+        ));
+        assert!(matches!(
+            space.resolve_physical(far),
+            Err(MemoryError::NotMapped(_))
+        ));
+
+        // page table exists but the page isn't mapped
+        let next = start + FRAME_SIZE;
+        assert!(matches!(
+            space.write_bytes(next, &[1, 2, 3]),
+            Err(MemoryError::NotMapped(_))
+        ));
+
+        // runs off the end of the mapping partway through
+        assert!(matches!(
+            space.fill_bytes(next - 0x10, 0x20, 0xaa),
+            Err(MemoryError::NotMapped(_))
+        ));
+        assert!(matches!(
+            space.get_absent_mapping(next),
+            Err(MemoryError::NotMapped(_))
+        ));
+    }
+
+    #[test]
+    fn accessing_on_demand() {
+        let mut p4 = PageTable::default();
+        let mut space = new_space(&mut p4);
+
+        let start = VirtualAddress::with_literal(0x10000);
+        space
+            .map_range(start, 0x3000, MapTarget::Any, MapFlags::Writeable)
+            .expect("mapping failed");
+        for page in 0..3 {
+            assert!(space.get_absent_mapping(start + page * FRAME_SIZE).is_ok());
+        }
+
+        // allocates only the page it touches, zeroed
+        let mut dst = [0xffu8; 8];
+        space
+            .read_bytes(start + 0x1ff8, &mut dst)
+            .expect("read failed");
+        assert_eq!(dst, [0; 8]);
+        assert!(space.get_absent_mapping(start).is_ok());
+        assert!(space.get_absent_mapping(start + FRAME_SIZE).is_err());
+        assert!(space.get_absent_mapping(start + 2 * FRAME_SIZE).is_ok());
+
+        // a write across the boundary allocates the next one too
+        space
+            .write_bytes(start + 0x1ffe, &[1, 2, 3, 4])
+            .expect("write failed");
+        assert!(space.get_absent_mapping(start + 2 * FRAME_SIZE).is_err());
+
+        let mut dst = [0u8; 4];
+        space
+            .read_bytes(start + 0x1ffe, &mut dst)
+            .expect("read failed");
+        assert_eq!(dst, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn resolving_physical() {
+        let mut p4 = PageTable::default();
+        let mut space = new_space(&mut p4);
+
+        let start = VirtualAddress::with_literal(0x10000);
+        space
+            .map_range(start, 0x2000, MapTarget::Any, MapFlags::Writeable)
+            .expect("mapping failed");
+
+        // allocated on demand by the first resolve, then stable
+        let frame = space.resolve_physical(start + FRAME_SIZE).unwrap();
+        assert_eq!(frame.0 % FRAME_SIZE, 0);
+        assert_eq!(
+            space.resolve_physical(start + FRAME_SIZE + 0x123).unwrap(),
+            frame + 0x123
+        );
+        assert_eq!(
+            space.resolve_physical(start + FRAME_SIZE + 0xfff).unwrap(),
+            frame + 0xfff
+        );
+
+        // different page, different frame
+        let other = space.resolve_physical(start).unwrap();
+        assert_ne!(other, frame);
+
+        // visible through the physical address
+        space
+            .write_bytes(start + FRAME_SIZE + 0x123, &[0x5a])
+            .expect("write failed");
+        assert_eq!(physical_bytes(frame + 0x123, 1), &[0x5a]);
+    }
+
+    #[test]
+    fn unmapping() {
+        let mut p4 = PageTable::default();
+        let mut space =
+            unsafe { RawAddressSpace::with_existing(P4::with_initialized(&mut p4), Memory::new()) };
+
+        let committed = VirtualAddress::with_literal(0x10000);
+        let on_demand = VirtualAddress::with_literal(0x13000);
+        space
+            .map_range(committed, 0x3000, MapTarget::Any, MapFlags::Commit)
+            .expect("mapping failed");
+        space
+            .map_range(on_demand, 0x2000, MapTarget::Any, MapFlags::Writeable)
+            .expect("mapping failed");
+
+        let first_frame = space.resolve_physical(committed).unwrap();
+
+        // includes a page that was never mapped
+        let mut freed = Vec::new();
+        space
+            .unmap_range(committed, 0x6000, |frame| freed.push(frame))
+            .expect("unmapping failed");
+
+        assert_eq!(freed.len(), 3);
+        assert!(freed.contains(&first_frame));
+        assert!(matches!(
+            space.resolve_physical(committed),
+            Err(MemoryError::NotMapped(_))
+        ));
+        assert!(space.get_absent_mapping(on_demand).is_err());
+
+        // already unmapped, and no page tables at all
+        space
+            .unmap_range(committed, 0x6000, |_| panic!("nothing to free"))
+            .expect("unmapping failed");
+        space
+            .unmap_range(VirtualAddress::with_literal(0x4000_0000), 0x1000, |_| {
+                panic!("nothing to free")
+            })
+            .expect("unmapping failed");
+    }
+
     #[test]
     fn mapping() {
         // main testing is done by the asserts in map_range e.g. exact number of pages is mapped
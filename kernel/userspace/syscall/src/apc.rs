@@ -0,0 +1,48 @@
+/// User context interrupted by an asynchronous procedure call (APC), saved by the kernel on the
+/// thread's user stack before entering the APC routine. The routine is called as
+/// `routine(arg, frame)` in the Windows x64 calling convention, and must finish with the
+/// `apc_return` syscall passing the frame back rather than returning.
+///
+/// The frame can be modified by the routine, e.g. to resume somewhere else.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ApcFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+
+    pub rip: u64,
+    pub rsp: u64,
+
+    /// Only the arithmetic flags, DF and TF are restored from the frame
+    pub rflags: u64,
+}
+
+/// Bytes below the interrupted stack pointer that are left untouched when the frame is pushed,
+/// in case the interrupted code was using them without adjusting rsp
+pub const APC_RED_ZONE: u64 = 128;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_layout() {
+        // keeps the stack 16 byte aligned
+        assert_eq!(core::mem::size_of::<ApcFrame>() % 16, 0);
+        assert_eq!(core::mem::align_of::<ApcFrame>(), 8);
+    }
+}
@@ -1,11 +1,87 @@
+use core::fmt::{Display, Formatter};
 use num_enum::TryFromPrimitive;
 
 /// Returned as negative i64
 #[repr(u64)]
-#[derive(TryFromPrimitive, Debug)]
+#[derive(TryFromPrimitive, Debug, Copy, Clone, Eq, PartialEq)]
 pub enum SyscallError {
     NotImplemented = 1,
     UnknownError,
+
+    /// No syscall with the given number
     InvalidSyscall,
+
+    /// An argument is out of range or inconsistent with the others
     InvalidArguments,
+
+    /// A pointer argument is outside of userspace, misaligned or not mapped
+    BadAddress,
+
+    /// The named process, thread, image etc doesn't exist
+    NotFound,
+
+    /// The caller isn't allowed to do this, e.g. raise a resource limit
+    PermissionDenied,
+
+    /// The operation would block but the caller asked not to
+    WouldBlock,
+
+    /// The kernel couldn't allocate memory
+    OutOfMemory,
+
+    /// A handle argument doesn't refer to an open handle of the right type
+    BadHandle,
+
+    /// A blocking operation was interrupted before it completed
+    Interrupted,
+
+    /// A resource limit of the calling process would be exceeded
+    LimitExceeded,
+
+    /// The thing being created already exists
+    AlreadyExists,
+
+    /// An executable image is malformed or unsupported
+    InvalidImage,
+}
+
+impl Display for SyscallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        use SyscallError::*;
+        let msg = match self {
+            NotImplemented => "not implemented",
+            UnknownError => "unknown error",
+            InvalidSyscall => "invalid syscall",
+            InvalidArguments => "invalid arguments",
+            BadAddress => "bad address",
+            NotFound => "not found",
+            PermissionDenied => "permission denied",
+            WouldBlock => "would block",
+            OutOfMemory => "out of memory",
+            BadHandle => "bad handle",
+            Interrupted => "interrupted",
+            LimitExceeded => "resource limit exceeded",
+            AlreadyExists => "already exists",
+            InvalidImage => "invalid executable image",
+        };
+
+        f.write_str(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn stable_numbers() {
+        // existing values must never change
+        assert_eq!(SyscallError::NotImplemented as u64, 1);
+        assert_eq!(SyscallError::InvalidArguments as u64, 4);
+        assert_eq!(
+            SyscallError::try_from(SyscallError::InvalidImage as u64),
+            Ok(SyscallError::InvalidImage)
+        );
+    }
 }
@@ -0,0 +1,83 @@
+use crate::number::{SyscallNumber, SYSCALL_COUNT};
+use num_enum::TryFromPrimitive;
+
+/// What happens when a process makes a syscall its filter doesn't allow. Later variants are
+/// stricter, and a filter's action can only be made stricter
+#[repr(u64)]
+#[derive(TryFromPrimitive, Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum FilterAction {
+    /// The syscall fails with `PermissionDenied`
+    Deny = 0,
+
+    /// The process is killed with [EXIT_CODE_SYSCALL_DENIED]
+    Kill,
+}
+
+/// Exit code of a process killed by its syscall filter
+pub const EXIT_CODE_SYSCALL_DENIED: u32 = 0xC000_0002;
+
+/// Mask with a bit set for every syscall
+pub const ALL_SYSCALLS: u64 = u64::MAX >> (64 - SYSCALL_COUNT);
+
+/// Syscalls a filter can't deny, so a process can always exit and return from an APC it has been
+/// interrupted by
+pub const ALWAYS_ALLOWED: u64 = syscall_mask(&[
+    SyscallNumber::Exit,
+    SyscallNumber::ExitThread,
+    SyscallNumber::ApcReturn,
+]);
+
+// every syscall needs a bit in the mask
+const _: () = [()][(SYSCALL_COUNT > 64) as usize];
+
+/// Mask of allowed syscalls for a filter, with the bit for each syscall number set
+pub const fn syscall_mask(numbers: &[SyscallNumber]) -> u64 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < numbers.len() {
+        mask |= 1 << numbers[i] as u64;
+        i += 1;
+    }
+
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        assert_eq!(syscall_mask(&[]), 0);
+        assert_eq!(syscall_mask(&[SyscallNumber::Log]), 1);
+        assert_eq!(
+            syscall_mask(&[SyscallNumber::Exit, SyscallNumber::Wait]),
+            0b110
+        );
+
+        assert_eq!(ALL_SYSCALLS.count_ones() as usize, SYSCALL_COUNT);
+        assert_eq!(ALL_SYSCALLS & ALWAYS_ALLOWED, ALWAYS_ALLOWED);
+    }
+
+    #[test]
+    fn always_allowed() {
+        assert_eq!(ALWAYS_ALLOWED.count_ones(), 3);
+        for number in &[
+            SyscallNumber::Exit,
+            SyscallNumber::ExitThread,
+            SyscallNumber::ApcReturn,
+        ] {
+            assert_ne!(ALWAYS_ALLOWED & syscall_mask(&[*number]), 0);
+        }
+        assert_eq!(ALWAYS_ALLOWED & syscall_mask(&[SyscallNumber::Log]), 0);
+    }
+
+    #[test]
+    fn stricter() {
+        assert!(FilterAction::Kill > FilterAction::Deny);
+        assert_eq!(
+            FilterAction::Deny.max(FilterAction::Kill),
+            FilterAction::Kill
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use core::fmt::{Debug, Formatter};
+use core::ops::BitOr;
+
+/// Access rights of a handle, which can only be narrowed when it is duplicated
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct HandleRights(u32);
+
+impl HandleRights {
+    pub const NONE: Self = Self(0);
+
+    /// Read the state of the object, e.g. the pid of a process
+    pub const QUERY: Self = Self(1 << 0);
+
+    /// Change the state of the object, e.g. the priority of a thread
+    pub const MODIFY: Self = Self(1 << 1);
+
+    /// Wait for the object to be signalled, e.g. a process to exit
+    pub const WAIT: Self = Self(1 << 2);
+
+    /// Duplicate the handle
+    pub const DUPLICATE: Self = Self(1 << 3);
+
+    /// Copy the handle into child processes when they are spawned
+    pub const INHERIT: Self = Self(1 << 4);
+
+    pub const ALL: Self = Self((1 << 5) - 1);
+
+    /// None if any unknown bits are set
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        if bits & !Self::ALL.0 == 0 {
+            Some(Self(bits))
+        } else {
+            None
+        }
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// True if all of the given rights are present
+    pub const fn contains(self, rights: Self) -> bool {
+        self.0 & rights.0 == rights.0
+    }
+
+    /// The given rights that are not present
+    pub const fn missing(self, rights: Self) -> Self {
+        Self(rights.0 & !self.0)
+    }
+}
+
+impl BitOr for HandleRights {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl Debug for HandleRights {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        const NAMES: [(HandleRights, &str); 5] = [
+            (HandleRights::QUERY, "QUERY"),
+            (HandleRights::MODIFY, "MODIFY"),
+            (HandleRights::WAIT, "WAIT"),
+            (HandleRights::DUPLICATE, "DUPLICATE"),
+            (HandleRights::INHERIT, "INHERIT"),
+        ];
+
+        if *self == Self::NONE {
+            return write!(f, "NONE");
+        }
+
+        let mut first = true;
+        for (right, name) in NAMES.iter() {
+            if self.contains(*right) {
+                if !first {
+                    write!(f, " | ")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bits() {
+        assert_eq!(HandleRights::from_bits(0), Some(HandleRights::NONE));
+        assert_eq!(HandleRights::from_bits(0b11111), Some(HandleRights::ALL));
+        assert_eq!(HandleRights::from_bits(1 << 5), None);
+        assert_eq!(HandleRights::from_bits(u32::MAX), None);
+    }
+
+    #[test]
+    fn contains() {
+        let rights = HandleRights::QUERY | HandleRights::WAIT;
+        assert!(rights.contains(HandleRights::QUERY));
+        assert!(rights.contains(HandleRights::NONE));
+        assert!(!rights.contains(HandleRights::QUERY | HandleRights::MODIFY));
+        assert_eq!(
+            rights.missing(HandleRights::QUERY | HandleRights::MODIFY),
+            HandleRights::MODIFY
+        );
+        assert!(HandleRights::ALL.contains(rights));
+    }
+
+    #[test]
+    fn debug() {
+        let rights = HandleRights::QUERY | HandleRights::DUPLICATE;
+        assert_eq!(format!("{:?}", rights), "QUERY | DUPLICATE");
+        assert_eq!(format!("{:?}", HandleRights::NONE), "NONE");
+    }
+}
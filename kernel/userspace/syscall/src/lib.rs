@@ -1,8 +1,30 @@
 #![cfg_attr(not(test), no_std)]
 #![feature(const_fn_transmute)]
+#![cfg_attr(feature = "userspace", feature(asm))]
 
+mod apc;
 mod error;
+mod filter;
+mod handle;
+mod number;
+mod params;
 mod result;
+mod shared;
 
+#[cfg(feature = "userspace")]
+pub mod userspace;
+
+pub use apc::{ApcFrame, APC_RED_ZONE};
 pub use error::SyscallError;
+pub use filter::{
+    syscall_mask, FilterAction, ALL_SYSCALLS, ALWAYS_ALLOWED, EXIT_CODE_SYSCALL_DENIED,
+};
+pub use handle::HandleRights;
+pub use number::{SyscallNumber, SYSCALL_COUNT};
+pub use params::{
+    encode_environment_block, environment_block_len, iter_environment_block, ProcessParameters,
+    SpawnParameters, ENVIRONMENT_SEPARATOR, MAX_COMMAND_LINE_LEN, MAX_ENVIRONMENT_LEN,
+    MAX_IMAGE_NAME_LEN,
+};
 pub use result::SyscallResult;
+pub use shared::{CpuFeatureRegister, KernelSharedData, KERNEL_SHARED_DATA_ADDRESS};
@@ -0,0 +1,51 @@
+use num_enum::TryFromPrimitive;
+
+/// Syscall numbers passed in rax, shared by the kernel's syscall table and the userspace wrappers
+#[repr(u64)]
+#[derive(TryFromPrimitive, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SyscallNumber {
+    Log = 0,
+    Exit,
+    Wait,
+    GetPriority,
+    SetPriority,
+    SetSegmentBase,
+    GetCpuTime,
+    GetResourceLimit,
+    SetResourceLimit,
+    QueueApc,
+    ApcReturn,
+    CreateThread,
+    ExitThread,
+    JoinThread,
+    Spawn,
+    SetSyscallTracing,
+    GetTime,
+    SleepUntil,
+    SleepFor,
+    CloseHandle,
+    DuplicateHandle,
+    OpenProcess,
+    OpenThread,
+    SetSyscallFilter,
+    FutexWait,
+    FutexWake,
+}
+
+/// Number of syscalls, all of which are numbered below this
+pub const SYSCALL_COUNT: usize = SyscallNumber::FutexWake as usize + 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn numbers_are_contiguous() {
+        for nr in 0..SYSCALL_COUNT as u64 {
+            assert_eq!(SyscallNumber::try_from(nr).unwrap() as u64, nr);
+        }
+
+        assert!(SyscallNumber::try_from(SYSCALL_COUNT as u64).is_err());
+    }
+}
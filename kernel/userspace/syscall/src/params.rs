@@ -0,0 +1,159 @@
+/// Process parameter block, mapped by the kernel into every new user process before it starts.
+/// A pointer to it is passed to the entry point in rcx (the first argument in the Windows x64
+/// calling convention used by PE images).
+///
+/// Addresses are all in the address space of the process. The command line and environment are
+/// UTF-8 and follow the block in the same mapping.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ProcessParameters {
+    /// Size of this struct in bytes, for forwards compatibility
+    pub size: u64,
+
+    pub pid: u64,
+
+    /// Address the executable image is mapped at
+    pub image_base: u64,
+
+    /// Top of the main thread's stack, i.e. the initial stack pointer
+    pub stack_top: u64,
+
+    /// Lowest address the main thread's stack can grow down to
+    pub stack_limit: u64,
+
+    pub command_line: u64,
+    pub command_line_len: u64,
+
+    /// Sequence of `KEY=VALUE` strings, each terminated by [ENVIRONMENT_SEPARATOR]
+    pub environment: u64,
+    pub environment_len: u64,
+}
+
+/// Arguments to the spawn syscall, passed by pointer. Strings are UTF-8 in the address space of
+/// the caller, and are copied before the new process is created.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SpawnParameters {
+    /// Name of the executable image to run
+    pub image_name: u64,
+    pub image_name_len: u64,
+
+    pub command_line: u64,
+    pub command_line_len: u64,
+
+    /// Environment of the new process as encoded by [encode_environment_block], may be empty
+    pub environment: u64,
+    pub environment_len: u64,
+}
+
+/// Longest image name accepted by spawn, in bytes
+pub const MAX_IMAGE_NAME_LEN: u64 = 256;
+
+/// Longest command line accepted by spawn, in bytes
+pub const MAX_COMMAND_LINE_LEN: u64 = 32 * 1024;
+
+/// Longest environment block accepted by spawn, in bytes
+pub const MAX_ENVIRONMENT_LEN: u64 = 32 * 1024;
+
+/// Terminates each variable in the environment block
+pub const ENVIRONMENT_SEPARATOR: u8 = b'\0';
+
+/// Bytes needed to encode the given variables as an environment block
+pub fn environment_block_len(vars: &[(&str, &str)]) -> usize {
+    vars.iter()
+        .map(|(key, value)| key.len() + 1 + value.len() + 1)
+        .sum()
+}
+
+/// Encodes the given variables as an environment block into `dst`, which must be at least
+/// [environment_block_len] long. Returns the number of bytes written
+pub fn encode_environment_block(vars: &[(&str, &str)], dst: &mut [u8]) -> usize {
+    let mut cursor = 0;
+    let mut push = |bytes: &[u8]| {
+        dst[cursor..cursor + bytes.len()].copy_from_slice(bytes);
+        cursor += bytes.len();
+    };
+
+    for (key, value) in vars {
+        push(key.as_bytes());
+        push(b"=");
+        push(value.as_bytes());
+        push(&[ENVIRONMENT_SEPARATOR]);
+    }
+
+    cursor
+}
+
+/// Iterates (key, value) pairs in an environment block. Entries without a `=` are skipped
+pub fn iter_environment_block(block: &str) -> impl Iterator<Item = (&str, &str)> {
+    block
+        .split(ENVIRONMENT_SEPARATOR as char)
+        .filter_map(|var| {
+            let mut split = var.splitn(2, '=');
+            let key = split.next()?;
+            let value = split.next()?;
+            Some((key, value))
+        })
+}
+
+#[cfg(feature = "userspace")]
+impl ProcessParameters {
+    /// # Safety
+    /// Must be the block passed to the entry point by the kernel
+    pub unsafe fn command_line(&self) -> &str {
+        Self::str_at(self.command_line, self.command_line_len)
+    }
+
+    /// # Safety
+    /// Must be the block passed to the entry point by the kernel
+    pub unsafe fn environment(&self) -> impl Iterator<Item = (&str, &str)> {
+        iter_environment_block(Self::str_at(self.environment, self.environment_len))
+    }
+
+    /// # Safety
+    /// Must be the block passed to the entry point by the kernel
+    pub unsafe fn env_var(&self, key: &str) -> Option<&str> {
+        self.environment()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    }
+
+    unsafe fn str_at<'a>(addr: u64, len: u64) -> &'a str {
+        if len == 0 {
+            return "";
+        }
+
+        // kernel guarantees utf8
+        let bytes = core::slice::from_raw_parts(addr as *const u8, len as usize);
+        core::str::from_utf8_unchecked(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_roundtrip() {
+        let vars = [("PATH", "/bin"), ("EMPTY", ""), ("EQUALS", "a=b")];
+        let len = environment_block_len(&vars);
+
+        let mut buf = [0xffu8; 64];
+        assert_eq!(encode_environment_block(&vars, &mut buf), len);
+
+        let block = core::str::from_utf8(&buf[..len]).unwrap();
+        assert_eq!(block, "PATH=/bin\0EMPTY=\0EQUALS=a=b\0");
+
+        let mut parsed = iter_environment_block(block);
+        assert_eq!(parsed.next(), Some(("PATH", "/bin")));
+        assert_eq!(parsed.next(), Some(("EMPTY", "")));
+        assert_eq!(parsed.next(), Some(("EQUALS", "a=b")));
+        assert_eq!(parsed.next(), None);
+    }
+
+    #[test]
+    fn empty_environment() {
+        assert_eq!(environment_block_len(&[]), 0);
+        assert_eq!(iter_environment_block("").next(), None);
+    }
+}
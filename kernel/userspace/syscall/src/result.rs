@@ -1,6 +1,8 @@
 use ux::u63;
 
 use crate::error::SyscallError;
+use core::convert::TryFrom;
+use core::fmt::{Debug, Formatter};
 
 // TODO feature gate "kernel"
 
@@ -20,8 +22,6 @@ pub struct SyscallOkResult(pub u63);
 pub fn parse_syscall_result<T: From<SyscallOkResult>>(
     returned: SyscallResult,
 ) -> Result<T, SyscallError> {
-    use core::convert::TryFrom;
-
     let signed: i64 = unsafe { core::mem::transmute(returned) };
     if signed.is_negative() {
         let err = SyscallError::try_from(signed.abs() as u64).unwrap_or(SyscallError::UnknownError);
@@ -60,6 +60,10 @@ impl SyscallResult {
     pub const fn to_u64(&self) -> u64 {
         self.0
     }
+
+    pub fn is_ok(&self) -> bool {
+        is_ok(self.0)
+    }
 }
 
 impl<T: Into<SyscallOkResult>> From<Result<T, SyscallError>> for SyscallResult {
@@ -71,6 +75,21 @@ impl<T: Into<SyscallOkResult>> From<Result<T, SyscallError>> for SyscallResult {
     }
 }
 
+/// Decoded as `Ok(value)` or `Err(error)`
+impl Debug for SyscallResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if is_ok(self.0) {
+            write!(f, "Ok({})", self.0)
+        } else {
+            let signed = self.0 as i64;
+            match SyscallError::try_from(signed.wrapping_neg() as u64) {
+                Ok(err) => write!(f, "Err({:?})", err),
+                Err(_) => write!(f, "Err({})", signed),
+            }
+        }
+    }
+}
+
 impl From<SyscallError> for SyscallResult {
     #[inline]
     fn from(err: SyscallError) -> Self {
@@ -126,6 +145,18 @@ impl From<()> for SyscallOkResult {
     }
 }
 
+impl From<SyscallOkResult> for u32 {
+    #[inline]
+    fn from(result: SyscallOkResult) -> Self {
+        u64::from(result.0) as u32
+    }
+}
+
+impl From<SyscallOkResult> for () {
+    #[inline]
+    fn from(_: SyscallOkResult) -> Self {}
+}
+
 #[cfg(test)]
 mod tests {
     use core::fmt::Debug;
@@ -152,12 +183,6 @@ mod tests {
         }
     }
 
-    impl From<SyscallOkResult> for u32 {
-        fn from(result: SyscallOkResult) -> Self {
-            u64::from(result.0) as u32
-        }
-    }
-
     fn check_ok<T>(val: T)
     where
         T: SyscallReturnable + Copy + Eq + Debug,
@@ -171,6 +196,15 @@ mod tests {
         assert_eq!(parsed.unwrap(), val)
     }
 
+    #[test]
+    fn debug() {
+        let ok = SyscallResult::try_ok(500_u64).unwrap();
+        let err = SyscallResult::from(SyscallError::InvalidArguments);
+        assert_eq!(format!("{:?}", ok), "Ok(500)");
+        assert_eq!(format!("{:?}", err), "Err(InvalidArguments)");
+        assert_eq!(format!("{:?}", SyscallResult(u64::MAX - 99)), "Err(-100)");
+    }
+
     #[test]
     fn parsing() {
         let err = SyscallResult::from(SyscallError::NotImplemented);
@@ -202,10 +236,6 @@ mod tests {
         let ok = Ok(());
         let err = Result::<(), SyscallError>::Err(SyscallError::InvalidArguments);
 
-        impl From<SyscallOkResult> for () {
-            fn from(_: SyscallOkResult) -> Self {}
-        }
-
         assert!(matches!(
             parse_syscall_result::<()>(SyscallResult::from(ok)),
             Ok(())
@@ -0,0 +1,178 @@
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+/// Address of the [KernelSharedData] page in every user process, where it is mapped read-only
+pub const KERNEL_SHARED_DATA_ADDRESS: u64 = 0x7ffe_0000;
+
+/// Index into [KernelSharedData::cpu_features] of each CPUID feature register
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CpuFeatureRegister {
+    /// Leaf 1 ecx
+    Leaf1Ecx = 0,
+    /// Leaf 1 edx
+    Leaf1Edx,
+    /// Leaf 7 subleaf 0 ebx
+    Leaf7Ebx,
+    /// Leaf 7 subleaf 0 ecx
+    Leaf7Ecx,
+}
+
+/// Page of kernel data readable by userspace without a syscall, like Windows' KUSER_SHARED_DATA.
+/// The tick fields are updated on every clock interrupt under [sequence](Self::sequence), so
+/// should be read together with [ticks](Self::ticks). The rest are constant after boot
+#[repr(C)]
+pub struct KernelSharedData {
+    /// Incremented before and after the tick fields are updated, so is odd while they are being
+    /// written
+    pub sequence: AtomicU64,
+
+    /// Clock interrupts since boot
+    pub tick_count: AtomicU64,
+
+    /// Nanoseconds since boot as of the latest clock interrupt
+    pub tick_ns: AtomicU64,
+
+    /// Unix time in nanoseconds at boot, from which wall-clock time is kept
+    pub boot_unix_ns: AtomicU64,
+
+    /// TSC value that time since boot is measured from
+    pub boot_tsc: AtomicU64,
+
+    /// Calibrated TSC frequency, 0 if the TSC isn't usable for timing
+    pub tsc_hz: AtomicU64,
+
+    /// CPUID feature registers, indexed by [CpuFeatureRegister]
+    pub cpu_features: [AtomicU32; 4],
+}
+
+impl KernelSharedData {
+    /// Only used by the kernel, which updates the fields in place
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            tick_count: AtomicU64::new(0),
+            tick_ns: AtomicU64::new(0),
+            boot_unix_ns: AtomicU64::new(0),
+            boot_tsc: AtomicU64::new(0),
+            tsc_hz: AtomicU64::new(0),
+            cpu_features: [
+                AtomicU32::new(0),
+                AtomicU32::new(0),
+                AtomicU32::new(0),
+                AtomicU32::new(0),
+            ],
+        }
+    }
+
+    /// Records a clock interrupt at `now_ns` since boot. Only called by the kernel, which must not
+    /// call it concurrently
+    pub fn record_tick(&self, now_ns: u64) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.tick_count.fetch_add(1, Ordering::Relaxed);
+        self.tick_ns.store(now_ns, Ordering::Relaxed);
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+
+    /// Consistent (tick count, nanoseconds since boot) as of the latest clock interrupt
+    pub fn ticks(&self) -> (u64, u64) {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 != 0 {
+                // mid update
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let count = self.tick_count.load(Ordering::Acquire);
+            let ns = self.tick_ns.load(Ordering::Acquire);
+
+            if self.sequence.load(Ordering::Acquire) == before {
+                return (count, ns);
+            }
+        }
+    }
+
+    /// Nanoseconds since boot at the given TSC value if the TSC is usable, otherwise as of the
+    /// latest clock interrupt
+    pub fn monotonic_ns(&self, tsc: u64) -> u64 {
+        let hz = self.tsc_hz.load(Ordering::Relaxed);
+        if hz == 0 {
+            return self.ticks().1;
+        }
+
+        let elapsed = tsc.saturating_sub(self.boot_tsc.load(Ordering::Relaxed));
+        (elapsed as u128 * 1_000_000_000 / hz as u128) as u64
+    }
+
+    /// Nanoseconds since 1970-01-01 00:00 UTC at the given TSC value, as
+    /// [monotonic_ns](Self::monotonic_ns)
+    pub fn unix_ns(&self, tsc: u64) -> u64 {
+        self.boot_unix_ns.load(Ordering::Relaxed) + self.monotonic_ns(tsc)
+    }
+
+    pub fn cpu_features(&self, register: CpuFeatureRegister) -> u32 {
+        self.cpu_features[register as usize].load(Ordering::Relaxed)
+    }
+
+    /// Whether the given bit of a CPUID feature register is set
+    pub fn has_cpu_feature(&self, register: CpuFeatureRegister, bit: u32) -> bool {
+        bit < 32 && self.cpu_features(register) & (1 << bit) != 0
+    }
+}
+
+impl Default for KernelSharedData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_in_page() {
+        assert!(core::mem::size_of::<KernelSharedData>() <= 4096);
+        assert_eq!(KERNEL_SHARED_DATA_ADDRESS % 4096, 0);
+    }
+
+    #[test]
+    fn ticks() {
+        let data = KernelSharedData::new();
+        assert_eq!(data.ticks(), (0, 0));
+
+        data.record_tick(1000);
+        data.record_tick(2500);
+        assert_eq!(data.ticks(), (2, 2500));
+        assert_eq!(data.sequence.load(Ordering::Relaxed) % 2, 0);
+
+        // no usable tsc
+        assert_eq!(data.monotonic_ns(123_456), 2500);
+    }
+
+    #[test]
+    fn tsc_time() {
+        let data = KernelSharedData::new();
+        data.boot_tsc.store(1_000, Ordering::Relaxed);
+        data.tsc_hz.store(2_000_000_000, Ordering::Relaxed);
+
+        assert_eq!(data.monotonic_ns(1_000), 0);
+        assert_eq!(data.monotonic_ns(5_000), 2_000);
+        assert_eq!(data.monotonic_ns(0), 0);
+
+        data.boot_unix_ns
+            .store(1_600_000_000_000_000_000, Ordering::Relaxed);
+        assert_eq!(data.unix_ns(5_000), 1_600_000_000_000_002_000);
+    }
+
+    #[test]
+    fn features() {
+        let data = KernelSharedData::new();
+        data.cpu_features[CpuFeatureRegister::Leaf1Edx as usize].store(1 << 4, Ordering::Relaxed);
+
+        assert!(data.has_cpu_feature(CpuFeatureRegister::Leaf1Edx, 4));
+        assert!(!data.has_cpu_feature(CpuFeatureRegister::Leaf1Edx, 5));
+        assert!(!data.has_cpu_feature(CpuFeatureRegister::Leaf1Ecx, 4));
+        assert!(!data.has_cpu_feature(CpuFeatureRegister::Leaf1Edx, 40));
+    }
+}
@@ -0,0 +1,270 @@
+//! Wrappers that issue each syscall from userspace. See the kernel's syscall README for their
+//! behaviour.
+
+use crate::number::SyscallNumber;
+use crate::result::parse_syscall_result;
+use crate::{
+    ApcFrame, FilterAction, HandleRights, KernelSharedData, SpawnParameters, SyscallError,
+    SyscallResult, KERNEL_SHARED_DATA_ADDRESS,
+};
+use core::sync::atomic::AtomicU32;
+
+/// APC routine, called with the argument passed to [queue_apc] and the interrupted context. Must
+/// finish with [apc_return] rather than returning
+pub type ApcRoutine = extern "win64" fn(arg: u64, frame: *mut ApcFrame) -> !;
+
+/// Thread entry point, called with the argument passed to [create_thread]. Must finish with
+/// [exit_thread] rather than returning
+pub type ThreadEntry = extern "win64" fn(arg: u64) -> !;
+
+/// Issues the syscall with all 6 argument registers, unused ones are ignored by the kernel
+#[inline]
+unsafe fn syscall(nr: SyscallNumber, args: [u64; 6]) -> SyscallResult {
+    syscall_extra(nr, args).0
+}
+
+/// Also returns the second value in rdx, which is 0 on error
+#[inline]
+unsafe fn syscall_extra(nr: SyscallNumber, args: [u64; 6]) -> (SyscallResult, u64) {
+    let result: u64;
+    let extra: u64;
+
+    // rbx can't be an operand, so it is swapped in and out around the syscall. The kernel
+    // preserves the same registers as the C ABI, other than rcx and r11 which sysret clobbers
+    asm!(
+        "xchg {arg2}, rbx",
+        "syscall",
+        "xchg {arg2}, rbx",
+        arg2 = inout(reg) args[2] => _,
+        inlateout("rax") nr as u64 => result,
+        inlateout("rdi") args[0] => _,
+        inlateout("rsi") args[1] => _,
+        inlateout("rdx") args[3] => extra,
+        inlateout("r8") args[4] => _,
+        inlateout("r9") args[5] => _,
+        lateout("rcx") _,
+        lateout("r10") _,
+        lateout("r11") _,
+    );
+
+    (core::mem::transmute::<u64, SyscallResult>(result), extra)
+}
+
+pub fn log(message: &str) -> Result<(), SyscallError> {
+    let args = [message.as_ptr() as u64, message.len() as u64, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::Log, args) })
+}
+
+/// Exits the calling process
+pub fn exit(exit_code: u32) -> ! {
+    unsafe {
+        syscall(SyscallNumber::Exit, [exit_code as u64, 0, 0, 0, 0, 0]);
+    }
+
+    unreachable!("exit returned")
+}
+
+/// Waits for the child the handle refers to, which needs [HandleRights::WAIT], to exit, or any
+/// child if None, and returns its pid and exit code
+pub fn wait(process: Option<u64>) -> Result<(u64, u32), SyscallError> {
+    let args = [process.unwrap_or(0), 0, 0, 0, 0, 0];
+    let (result, exit_code) = unsafe { syscall_extra(SyscallNumber::Wait, args) };
+    let pid = parse_syscall_result(result)?;
+    Ok((pid, exit_code as u32))
+}
+
+/// Returns `class << 8 | level` of the thread the handle refers to, which needs
+/// [HandleRights::QUERY], or the calling thread if 0
+pub fn get_priority(thread: u64) -> Result<u32, SyscallError> {
+    parse_syscall_result(unsafe { syscall(SyscallNumber::GetPriority, [thread, 0, 0, 0, 0, 0]) })
+}
+
+/// The thread handle needs [HandleRights::MODIFY], or is 0 for the calling thread
+pub fn set_priority(thread: u64, class: u8, level: u8) -> Result<(), SyscallError> {
+    let args = [thread, class as u64, level as u64, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::SetPriority, args) })
+}
+
+/// Segment is FS (0) or GS (1)
+///
+/// # Safety
+/// Changes where thread-local storage is found, which the caller must expect
+pub unsafe fn set_segment_base(segment: u64, base: u64) -> Result<(), SyscallError> {
+    parse_syscall_result(syscall(
+        SyscallNumber::SetSegmentBase,
+        [segment, base, 0, 0, 0, 0],
+    ))
+}
+
+/// Nanoseconds of CPU time used by the calling thread (scope 0) or its process (scope 1), in user
+/// (mode 0) or kernel (mode 1) mode
+pub fn get_cpu_time(scope: u64, mode: u64) -> Result<u64, SyscallError> {
+    parse_syscall_result(unsafe { syscall(SyscallNumber::GetCpuTime, [scope, mode, 0, 0, 0, 0]) })
+}
+
+pub fn get_resource_limit(resource: u64) -> Result<u64, SyscallError> {
+    let args = [resource, 0, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::GetResourceLimit, args) })
+}
+
+pub fn set_resource_limit(resource: u64, limit: u64) -> Result<(), SyscallError> {
+    let args = [resource, limit, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::SetResourceLimit, args) })
+}
+
+/// Queues `routine(arg, frame)` to run on the thread the handle refers to, which needs
+/// [HandleRights::MODIFY], or the calling thread if 0
+pub fn queue_apc(thread: u64, routine: ApcRoutine, arg: u64) -> Result<(), SyscallError> {
+    let args = [thread, routine as usize as u64, arg, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::QueueApc, args) })
+}
+
+/// Resumes the context in the frame passed to an APC routine. Only returns on failure
+///
+/// # Safety
+/// Replaces every register, so the frame must be one the calling thread can continue from
+pub unsafe fn apc_return(frame: &ApcFrame) -> SyscallError {
+    let args = [frame as *const ApcFrame as u64, 0, 0, 0, 0, 0];
+    match parse_syscall_result::<()>(syscall(SyscallNumber::ApcReturn, args)) {
+        Err(err) => err,
+        Ok(()) => unreachable!("apc_return returned"),
+    }
+}
+
+/// Starts a new thread in the calling process and returns its tid
+pub fn create_thread(entry: ThreadEntry, arg: u64) -> Result<u64, SyscallError> {
+    let args = [entry as usize as u64, arg, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::CreateThread, args) })
+}
+
+/// Exits the calling thread, which can then be joined to retrieve `exit_value`
+pub fn exit_thread(exit_value: u64) -> ! {
+    unsafe {
+        syscall(SyscallNumber::ExitThread, [exit_value, 0, 0, 0, 0, 0]);
+    }
+
+    unreachable!("exit_thread returned")
+}
+
+/// Waits for the thread in the calling process the handle refers to, which needs
+/// [HandleRights::WAIT], to exit and returns its exit value
+pub fn join_thread(thread: u64) -> Result<u64, SyscallError> {
+    let args = [thread, 0, 0, 0, 0, 0];
+    let (result, exit_value) = unsafe { syscall_extra(SyscallNumber::JoinThread, args) };
+    parse_syscall_result::<()>(result)?;
+    Ok(exit_value)
+}
+
+/// Starts a child process running the named executable and returns its pid. The environment is
+/// encoded with [encode_environment_block](crate::encode_environment_block)
+pub fn spawn(
+    image_name: &str,
+    command_line: &str,
+    environment: &[u8],
+) -> Result<u64, SyscallError> {
+    let params = SpawnParameters {
+        image_name: image_name.as_ptr() as u64,
+        image_name_len: image_name.len() as u64,
+        command_line: command_line.as_ptr() as u64,
+        command_line_len: command_line.len() as u64,
+        environment: environment.as_ptr() as u64,
+        environment_len: environment.len() as u64,
+    };
+
+    let args = [&params as *const SpawnParameters as u64, 0, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::Spawn, args) })
+}
+
+/// Logs syscalls made by the calling process (scope 0) and children it creates afterwards, or by
+/// all processes (scope 1), which fails with [SyscallError::PermissionDenied] from userspace
+pub fn set_syscall_tracing(scope: u64, enabled: bool) -> Result<(), SyscallError> {
+    let args = [scope, enabled as u64, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::SetSyscallTracing, args) })
+}
+
+/// Nanoseconds since boot (clock 0) or since 1970-01-01 00:00 UTC (clock 1)
+pub fn get_time(clock: u64) -> Result<u64, SyscallError> {
+    parse_syscall_result(unsafe { syscall(SyscallNumber::GetTime, [clock, 0, 0, 0, 0, 0]) })
+}
+
+/// Blocks the calling thread until the given nanoseconds since boot
+pub fn sleep_until(deadline_ns: u64) -> Result<(), SyscallError> {
+    let args = [deadline_ns, 0, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::SleepUntil, args) })
+}
+
+/// Blocks the calling thread for at least the given number of nanoseconds
+pub fn sleep_for(duration_ns: u64) -> Result<(), SyscallError> {
+    let args = [duration_ns, 0, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::SleepFor, args) })
+}
+
+pub fn close_handle(handle: u64) -> Result<(), SyscallError> {
+    parse_syscall_result(unsafe { syscall(SyscallNumber::CloseHandle, [handle, 0, 0, 0, 0, 0]) })
+}
+
+/// Opens another handle to the same object with the given rights, which must be a subset of the
+/// original's
+pub fn duplicate_handle(handle: u64, rights: HandleRights) -> Result<u64, SyscallError> {
+    let args = [handle, rights.bits() as u64, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::DuplicateHandle, args) })
+}
+
+/// Opens a handle to the calling process if pid is 0, otherwise one of its children
+pub fn open_process(pid: u64, rights: HandleRights) -> Result<u64, SyscallError> {
+    let args = [pid, rights.bits() as u64, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::OpenProcess, args) })
+}
+
+/// Opens a handle to the given thread in the calling process, or the calling thread if 0
+pub fn open_thread(tid: u64, rights: HandleRights) -> Result<u64, SyscallError> {
+    let args = [tid, rights.bits() as u64, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::OpenThread, args) })
+}
+
+/// Restricts the syscalls made by the calling process and children it creates afterwards (scope 0),
+/// or only by children it creates afterwards (scope 1), to those in `allowed`, a mask built with
+/// [syscall_mask](crate::syscall_mask). Filters can only be tightened
+pub fn set_syscall_filter(
+    scope: u64,
+    allowed: u64,
+    action: FilterAction,
+) -> Result<(), SyscallError> {
+    let args = [scope, allowed, action as u64, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::SetSyscallFilter, args) })
+}
+
+/// Blocks the calling thread until woken by [futex_wake] on the same word, unless it no longer
+/// equals `expected`, in which case this fails with [SyscallError::WouldBlock]. Wakeups may be
+/// spurious, so recheck the word in a loop
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> Result<(), SyscallError> {
+    let args = [word as *const AtomicU32 as u64, expected as u64, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::FutexWait, args) })
+}
+
+/// Wakes up to `count` threads waiting on `word` in any process sharing it, and returns how many
+/// were woken
+pub fn futex_wake(word: &AtomicU32, count: u64) -> Result<u64, SyscallError> {
+    let args = [word as *const AtomicU32 as u64, count, 0, 0, 0, 0];
+    parse_syscall_result(unsafe { syscall(SyscallNumber::FutexWake, args) })
+}
+
+/// The kernel's shared data page, which is mapped read-only into every process
+pub fn kernel_shared_data() -> &'static KernelSharedData {
+    // safety: always mapped by the kernel and never unmapped
+    unsafe { &*(KERNEL_SHARED_DATA_ADDRESS as *const KernelSharedData) }
+}
+
+/// Nanoseconds since boot, read from the shared data page without a syscall
+pub fn monotonic_ns() -> u64 {
+    // safety: rdtsc is always available
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    kernel_shared_data().monotonic_ns(tsc)
+}
+
+/// Nanoseconds since 1970-01-01 00:00 UTC, read from the shared data page without a syscall
+pub fn unix_ns() -> u64 {
+    // safety: rdtsc is always available
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    kernel_shared_data().unix_ns(tsc)
+}